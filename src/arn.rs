@@ -0,0 +1,134 @@
+use std::{error::Error, fmt, str::FromStr};
+
+// Partitions execute-api is available in
+pub const PARTITIONS: [&str; 3] = ["aws", "aws-cn", "aws-us-gov"];
+
+// The methodArn of REST authorizers and the routeArn of HTTP API authorizers:
+// arn:<partition>:execute-api:<region>:<account>:<api id>/<stage>/<method>/<resource path>
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodArn {
+    pub partition: String,
+    pub region: String,
+    pub account_id: String,
+    pub api_id: String,
+    pub stage: String,
+    // the route key for WebSocket APIs ($connect)
+    pub method: Option<String>,
+    // starts with a /, empty when the ARN stops at the method
+    pub resource_path: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MethodArnError {
+    NotAnArn(String),
+    UnsupportedPartition(String),
+    UnsupportedService(String),
+    MissingField(&'static str),
+    InvalidAccountId(String),
+}
+
+impl Error for MethodArnError {}
+
+impl fmt::Display for MethodArnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MethodArnError::NotAnArn(arn) => write!(f, "{:?} is not an ARN", arn),
+            MethodArnError::UnsupportedPartition(partition) => write!(f, "Unsupported partition {:?}", partition),
+            MethodArnError::UnsupportedService(service) => write!(f, "Unsupported service {:?}, expected execute-api", service),
+            MethodArnError::MissingField(field) => write!(f, "Method ARN has no {}", field),
+            MethodArnError::InvalidAccountId(account_id) => write!(f, "Invalid account id {:?}", account_id),
+        }
+    }
+}
+
+impl FromStr for MethodArn {
+    type Err = MethodArnError;
+
+    fn from_str(arn: &str) -> Result<Self, Self::Err> {
+        // the resource path may contain colons too
+        let fields: Vec<&str> = arn.splitn(6, ':').collect();
+        let (partition, service, region, account_id, resource) = match fields[..] {
+            ["arn", partition, service, region, account_id, resource] => (partition, service, region, account_id, resource),
+            _ => return Err(MethodArnError::NotAnArn(arn.to_string())),
+        };
+        if !PARTITIONS.contains(&partition) {
+            return Err(MethodArnError::UnsupportedPartition(partition.to_string()));
+        }
+        if service != "execute-api" {
+            return Err(MethodArnError::UnsupportedService(service.to_string()));
+        }
+        if region.is_empty() {
+            return Err(MethodArnError::MissingField("region"));
+        }
+        if account_id.len() != 12 || !account_id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(MethodArnError::InvalidAccountId(account_id.to_string()));
+        }
+        let mut segments = resource.splitn(4, '/');
+        let api_id = match segments.next() {
+            Some(api_id) if !api_id.is_empty() => api_id,
+            _ => return Err(MethodArnError::MissingField("API id")),
+        };
+        let stage = match segments.next() {
+            Some(stage) if !stage.is_empty() => stage,
+            _ => return Err(MethodArnError::MissingField("stage")),
+        };
+        let method = segments.next().filter(|method| !method.is_empty()).map(str::to_string);
+        let resource_path = segments.next().map_or_else(String::new, |path| format!("/{}", path));
+        Ok(Self {
+            partition: partition.to_string(),
+            region: region.to_string(),
+            account_id: account_id.to_string(),
+            api_id: api_id.to_string(),
+            stage: stage.to_string(),
+            method,
+            resource_path,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_method_arn() {
+        let arn: MethodArn = "arn:aws:execute-api:eu-west-1:481724841148:5q06q4o1qe/prod/GET/boto/sdfdsfsdfs".parse().unwrap();
+        assert_eq!(arn, MethodArn {
+            partition: "aws".to_string(),
+            region: "eu-west-1".to_string(),
+            account_id: "481724841148".to_string(),
+            api_id: "5q06q4o1qe".to_string(),
+            stage: "prod".to_string(),
+            method: Some("GET".to_string()),
+            resource_path: "/boto/sdfdsfsdfs".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_parse_other_partitions_and_apis() {
+        let arn: MethodArn = "arn:aws-cn:execute-api:cn-north-1:481724841148:5q06q4o1qe/$default/POST/users/auth0:123".parse().unwrap();
+        assert_eq!((arn.partition.as_str(), arn.stage.as_str()), ("aws-cn", "$default"));
+        assert_eq!(arn.resource_path, "/users/auth0:123");
+        let arn: MethodArn = "arn:aws-us-gov:execute-api:us-gov-west-1:481724841148:5q06q4o1qe/prod/GET/".parse().unwrap();
+        assert_eq!(arn.resource_path, "/");
+        // WebSocket APIs have a route key instead of a method and path
+        let arn: MethodArn = "arn:aws:execute-api:eu-west-1:481724841148:5q06q4o1qe/prod/$connect".parse().unwrap();
+        assert_eq!(arn.method.as_deref(), Some("$connect"));
+        assert_eq!(arn.resource_path, "");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |arn: &str| arn.parse::<MethodArn>().unwrap_err();
+        assert_eq!(error(""), MethodArnError::NotAnArn("".to_string()));
+        assert_eq!(error("arn:aws:execute-api:eu-west-1"), MethodArnError::NotAnArn("arn:aws:execute-api:eu-west-1".to_string()));
+        assert_eq!(error("arn:aws-iso:execute-api:eu-west-1:481724841148:abc/prod"), MethodArnError::UnsupportedPartition("aws-iso".to_string()));
+        assert_eq!(error("arn:aws:lambda:eu-west-1:481724841148:abc/prod"), MethodArnError::UnsupportedService("lambda".to_string()));
+        assert_eq!(error("arn:aws:execute-api::481724841148:abc/prod"), MethodArnError::MissingField("region"));
+        assert_eq!(error("arn:aws:execute-api:eu-west-1:4817:abc/prod"), MethodArnError::InvalidAccountId("4817".to_string()));
+        assert_eq!(error("arn:aws:execute-api:eu-west-1:481724841148:"), MethodArnError::MissingField("API id"));
+        assert_eq!(error("arn:aws:execute-api:eu-west-1:481724841148:abc"), MethodArnError::MissingField("stage"));
+        assert_eq!(error("arn:aws:execute-api:eu-west-1:481724841148:abc/").to_string(), "Method ARN has no stage");
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail};
+use fehler::throws;
+use log::{debug, info};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::enums::{Decision, FailureCategory};
+
+// previous_hash of the first record of a chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// One authorization decision
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    // milliseconds since the epoch
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    // see redaction::fingerprint, the token itself is never recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    pub method_arn: String,
+    pub decision: Decision,
+    // the route rule that granted the call, * when all routes are allowed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_category: Option<FailureCategory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+}
+
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord) -> anyhow::Result<()>;
+}
+
+// One JSON object per line on stdout, next to the logs
+pub struct StdoutAuditSink;
+
+impl AuditSink for StdoutAuditSink {
+    #[throws(anyhow::Error)]
+    fn record(&self, record: &AuditRecord) {
+        let line = json!({ "audit": record });
+        writeln!(std::io::stdout().lock(), "{}", line)?;
+    }
+}
+
+// Each line holds the hash of the previous one, keyed so that only the holders
+// of the key can rewrite the chain, and changing, removing or reordering records
+// breaks it (see verify_chain). The head of the chain is logged after each record,
+// so that removing the last records can be told too.
+pub struct FileAuditSink {
+    pub path: PathBuf,
+    key: Vec<u8>,
+    // the file, the sequence number and the hash of the last record
+    chain: Mutex<(File, u64, String)>,
+}
+
+impl FileAuditSink {
+    // Appends to the chain already in the file, if any
+    #[throws(anyhow::Error)]
+    pub fn open<P: AsRef<Path>>(path: P, key: &[u8]) -> Self {
+        if key.is_empty() {
+            bail!("The audit key is empty")
+        }
+        let path = path.as_ref().to_path_buf();
+        let (sequence, last_hash) = match File::open(&path) {
+            Ok(file) => match BufReader::new(file).lines().last() {
+                Some(line) => {
+                    let line: Value = serde_json::from_str(&line?)?;
+                    (sequence_of(&line)?, hash_of(&line)?.to_string())
+                },
+                None => (0, GENESIS_HASH.to_string()),
+            },
+            Err(_) => (0, GENESIS_HASH.to_string()),
+        };
+        debug!(target: "audit.open", "Audit log {} continues after record {}", path.display(), sequence);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Self { path, key: key.to_vec(), chain: Mutex::new((file, sequence, last_hash)) }
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    #[throws(anyhow::Error)]
+    fn record(&self, record: &AuditRecord) {
+        let mut chain = self.chain.lock().map_err(|_| anyhow!("Audit log {} is poisoned", self.path.display()))?;
+        let (file, sequence, last_hash) = &mut *chain;
+        let record = serde_json::to_value(record)?;
+        let line = chained(&self.key, *sequence + 1, last_hash, record)?;
+        // a single write per record, so a crash cannot leave half a line behind another one
+        file.write_all(format!("{}\n", line).as_bytes())?;
+        file.flush()?;
+        *sequence += 1;
+        *last_hash = hash_of(&line)?.to_string();
+        info!(target: "audit.head", "Audit log {} head is {}:{}", self.path.display(), sequence, last_hash);
+    }
+}
+
+#[throws(anyhow::Error)]
+fn chained(key: &[u8], sequence: u64, previous_hash: &str, record: Value) -> Value {
+    let hash = chain_hash(key, sequence, previous_hash, &record)?;
+    json!({ "sequence": sequence, "previous_hash": previous_hash, "record": record, "hash": hash })
+}
+
+// HMAC-SHA256, serde_json sorts object keys so the serialized record is canonical
+#[throws(anyhow::Error)]
+fn chain_hash(key: &[u8], sequence: u64, previous_hash: &str, record: &Value) -> String {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    let digest = signer.sign_oneshot_to_vec(format!("{}:{}:{}", sequence, previous_hash, record).as_bytes())?;
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[throws(anyhow::Error)]
+fn sequence_of(line: &Value) -> u64 {
+    match line["sequence"].as_u64() {
+        Some(sequence) => sequence,
+        None => bail!("Audit record has no sequence number"),
+    }
+}
+
+#[throws(anyhow::Error)]
+fn hash_of(line: &Value) -> &str {
+    match line["hash"].as_str() {
+        Some(hash) => hash,
+        None => bail!("Audit record has no hash"),
+    }
+}
+
+// Checks every link of the chain with the key it was written with, and that the
+// chain goes through the expected head (sequence and hash) logged by the sink, if
+// any. Returns the number of records.
+#[throws(anyhow::Error)]
+pub fn verify_chain<P: AsRef<Path>>(path: P, key: &[u8], head: Option<(u64, &str)>) -> u64 {
+    let mut previous_hash = GENESIS_HASH.to_string();
+    let mut count = 0;
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let number = index as u64 + 1;
+        let line: Value = serde_json::from_str(&line?).map_err(|error| anyhow!("Line {} is not an audit record: {}", number, error))?;
+        if sequence_of(&line)? != number {
+            bail!("Line {} holds record {}, records are missing or out of order", number, sequence_of(&line)?)
+        }
+        if line["previous_hash"] != previous_hash.as_str() {
+            bail!("Record {} does not follow record {}", number, number - 1)
+        }
+        if chain_hash(key, number, &previous_hash, &line["record"])? != hash_of(&line)? {
+            bail!("Record {} has been tampered with", number)
+        }
+        if let Some((sequence, hash)) = head {
+            if sequence == number && hash != hash_of(&line)? {
+                bail!("Record {} is not the expected head", number)
+            }
+        }
+        previous_hash = hash_of(&line)?.to_string();
+        count = number;
+    }
+    if let Some((sequence, _)) = head {
+        if count < sequence {
+            bail!("The log ends at record {}, records up to {} are missing", count, sequence)
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+
+    const KEY: &[u8] = b"audit key";
+
+    fn record(decision: Decision) -> AuditRecord {
+        AuditRecord {
+            timestamp: 1600000000000,
+            principal: Some("auth0|123456".to_string()),
+            jti: Some("a4f3c2".to_string()),
+            token_fingerprint: Some("sha256:0123456789abcdef".to_string()),
+            issuer: Some("https://boto.eu.auth0.com/".to_string()),
+            method_arn: "arn:aws:execute-api:eu-west-1:481724841148:5q06q4o1qe/prod/GET/botos".to_string(),
+            decision,
+            matched_rule: Some("GET /botos".to_string()),
+            failure_category: None,
+            failure_reason: None,
+        }
+    }
+
+    fn audit_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("jwt_authorizer-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn edit_line<F: FnOnce(&mut Vec<String>)>(path: &Path, edit: F) {
+        let mut lines: Vec<String> = fs::read_to_string(path).unwrap().lines().map(str::to_string).collect();
+        edit(&mut lines);
+        fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[test]
+    fn test_chain_survives_reopening() {
+        let path = audit_log("reopen");
+        let sink = FileAuditSink::open(&path, KEY).unwrap();
+        sink.record(&record(Decision::Allow)).unwrap();
+        sink.record(&record(Decision::Deny)).unwrap();
+        drop(sink);
+        let sink = FileAuditSink::open(&path, KEY).unwrap();
+        sink.record(&record(Decision::Unauthorized)).unwrap();
+        assert_eq!(verify_chain(&path, KEY, None).unwrap(), 3);
+        assert_eq!(verify_chain(&path, b"other key", None).unwrap_err().to_string(), "Record 1 has been tampered with");
+        let lines: Vec<Value> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines[0]["previous_hash"], GENESIS_HASH);
+        assert_eq!(lines[2]["previous_hash"], lines[1]["hash"]);
+        assert_eq!(lines[2]["record"]["decision"], "unauthorized");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let path = audit_log("tampering");
+        let sink = FileAuditSink::open(&path, KEY).unwrap();
+        for decision in [Decision::Allow, Decision::Deny, Decision::Allow] {
+            sink.record(&record(decision)).unwrap();
+        }
+        let original = fs::read_to_string(&path).unwrap();
+        edit_line(&path, |lines| lines[1] = lines[1].replace("\"deny\"", "\"allow\""));
+        assert_eq!(verify_chain(&path, KEY, None).unwrap_err().to_string(), "Record 2 has been tampered with");
+        fs::write(&path, &original).unwrap();
+        edit_line(&path, |lines| { lines.remove(1); });
+        assert!(verify_chain(&path, KEY, None).unwrap_err().to_string().contains("missing or out of order"));
+        fs::write(&path, &original).unwrap();
+        edit_line(&path, |lines| lines.swap(1, 2));
+        assert!(verify_chain(&path, KEY, None).is_err());
+        // rewriting a record along with its hash still breaks the next link
+        fs::write(&path, &original).unwrap();
+        edit_line(&path, |lines| {
+            let line: Value = serde_json::from_str(&lines[1]).unwrap();
+            let mut forged = line["record"].clone();
+            forged["decision"] = json!("allow");
+            lines[1] = chained(KEY, 2, line["previous_hash"].as_str().unwrap(), forged).unwrap().to_string();
+        });
+        assert_eq!(verify_chain(&path, KEY, None).unwrap_err().to_string(), "Record 3 does not follow record 2");
+        // without the key, rewriting the rest of the chain does not help
+        fs::write(&path, &original).unwrap();
+        edit_line(&path, |lines| {
+            let mut previous_hash = serde_json::from_str::<Value>(&lines[0]).unwrap()["hash"].as_str().unwrap().to_string();
+            for (index, line) in lines.iter_mut().enumerate().skip(1) {
+                let mut forged = serde_json::from_str::<Value>(line).unwrap()["record"].clone();
+                forged["decision"] = json!("allow");
+                let chained = chained(b"guessed key", index as u64 + 1, &previous_hash, forged).unwrap();
+                previous_hash = chained["hash"].as_str().unwrap().to_string();
+                *line = chained.to_string();
+            }
+        });
+        assert_eq!(verify_chain(&path, KEY, None).unwrap_err().to_string(), "Record 2 has been tampered with");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncation_is_detected_with_the_head() {
+        let path = audit_log("truncation");
+        let sink = FileAuditSink::open(&path, KEY).unwrap();
+        for decision in [Decision::Allow, Decision::Deny, Decision::Allow] {
+            sink.record(&record(decision)).unwrap();
+        }
+        let lines: Vec<Value> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let head = (3, lines[2]["hash"].as_str().unwrap());
+        assert_eq!(verify_chain(&path, KEY, Some(head)).unwrap(), 3);
+        // an older head is still on the chain
+        assert_eq!(verify_chain(&path, KEY, Some((2, lines[1]["hash"].as_str().unwrap()))).unwrap(), 3);
+        assert_eq!(verify_chain(&path, KEY, Some((2, lines[2]["hash"].as_str().unwrap()))).unwrap_err().to_string(), "Record 2 is not the expected head");
+        edit_line(&path, |lines| { lines.pop(); });
+        assert_eq!(verify_chain(&path, KEY, None).unwrap(), 2);
+        assert_eq!(verify_chain(&path, KEY, Some(head)).unwrap_err().to_string(), "The log ends at record 2, records up to 3 are missing");
+        assert!(FileAuditSink::open(&path, b"").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+use std::{env, process};
+
+use jwt_authorizer::audit;
+
+// Checks the hash chain of an audit log written by the authorizer (JWTAUTH_AUDIT_LOG=file:<path>)
+// with the key it was written with (JWTAUTH_AUDIT_KEY), and that it goes through the
+// head logged by the authorizer (audit.head), when given
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| usage());
+    let key = env::var("JWTAUTH_AUDIT_KEY").unwrap_or_else(|_| usage());
+    let head = env::args().nth(2);
+    let head = match head.as_deref().map(|head| head.split_once(':')) {
+        Some(Some((sequence, hash))) => Some((sequence.parse().unwrap_or_else(|_| usage()), hash)),
+        Some(None) => usage(),
+        None => None,
+    };
+    match audit::verify_chain(&path, key.as_bytes(), head) {
+        Ok(count) => println!("{}: {} records, chain is intact", path, count),
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            process::exit(1);
+        },
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: JWTAUTH_AUDIT_KEY=<key> audit_verify <audit log> [<sequence>:<hash>]");
+    process::exit(2);
+}
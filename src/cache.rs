@@ -0,0 +1,213 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use fehler::throws;
+use log::debug;
+use reqwest::header::{HeaderMap, CACHE_CONTROL, EXPIRES};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{field, info_span, Instrument};
+
+use crate::{enums::FailureCategory, metrics, structs::{AuthError, KeySet, JWK}, telemetry, utils};
+
+pub const DEFAULT_JWKS_TTL: Duration = Duration::from_secs(600);
+pub const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+struct CachedJwks {
+    key_set: KeySet,
+    expires_at: Instant,
+}
+
+impl CachedJwks {
+    fn is_fresh(&self) -> bool {
+        Instant::now() < self.expires_at
+    }
+}
+
+// Keeps the JWKS around for the lifetime of the (warm) Lambda container.
+// Concurrent invocations share a single cache entry and at most one of them
+// downloads the key set at any given time.
+pub struct JwksCache {
+    pub url: String,
+    pub default_ttl: Duration,
+    pub min_refresh_interval: Duration,
+    client: reqwest::Client,
+    entry: RwLock<Option<CachedJwks>>,
+    // also remembers when the key set was last refreshed because of an unknown kid
+    fetch_lock: Mutex<Option<Instant>>,
+}
+
+impl JwksCache {
+    pub fn new(url: String, default_ttl: Duration) -> Self {
+        debug!(target: "cache.new", "New JWKS cache (url: {}, ttl: {:?})", url, default_ttl);
+        Self {
+            url,
+            default_ttl,
+            min_refresh_interval: DEFAULT_MIN_REFRESH_INTERVAL,
+            client: reqwest::Client::new(),
+            entry: RwLock::new(None),
+            fetch_lock: Mutex::new(None),
+        }
+    }
+
+    pub fn with_min_refresh_interval(mut self, min_refresh_interval: Duration) -> Self {
+        self.min_refresh_interval = min_refresh_interval;
+        self
+    }
+
+    #[throws(anyhow::Error)]
+    pub async fn get_keys(&self) -> Vec<JWK> {
+        self.get_key_set().await?.accepted
+    }
+
+    // Also reports the keys that were skipped when the set was loaded
+    #[throws(anyhow::Error)]
+    pub async fn get_key_set(&self) -> KeySet {
+        if let Some(key_set) = self.fresh_key_set().await {
+            debug!(target: "cache.get_key_set", "Cache hit");
+            metrics::count(metrics::JWKS_CACHE_HIT);
+            return key_set;
+        }
+        let _guard = self.fetch_lock.lock().await;
+        // another invocation may have refreshed the keys while we were waiting
+        if let Some(key_set) = self.fresh_key_set().await {
+            debug!(target: "cache.get_key_set", "Cache refreshed while waiting");
+            metrics::count(metrics::JWKS_CACHE_HIT);
+            return key_set;
+        }
+        debug!(target: "cache.get_key_set", "Cache miss, fetching {}", self.url);
+        metrics::count(metrics::JWKS_CACHE_MISS);
+        self.fetch().await?
+    }
+
+    // Same as get_keys, but re-downloads the key set once when the kid is
+    // unknown (e.g. the IdP rotated its keys). Forced refreshes are coalesced
+    // and never happen more often than min_refresh_interval, so tokens with
+    // random kids cannot be used to hammer the JWKS endpoint.
+    #[throws(anyhow::Error)]
+    pub async fn get_keys_for_kid(&self, kid: &str) -> Vec<JWK> {
+        let keys = self.get_keys().await?;
+        if contains_kid(&keys, kid) {
+            return keys;
+        }
+        let mut last_forced_refresh = self.fetch_lock.lock().await;
+        // a concurrent miss may have refreshed the keys while we were waiting
+        if let Some(key_set) = self.fresh_key_set().await {
+            if contains_kid(&key_set.accepted, kid) {
+                debug!(target: "cache.get_keys_for_kid", "Kid {} found after concurrent refresh", kid);
+                return key_set.accepted;
+            }
+        }
+        if let Some(refreshed_at) = *last_forced_refresh {
+            if refreshed_at.elapsed() < self.min_refresh_interval {
+                debug!(target: "cache.get_keys_for_kid", "Kid {} not found, refresh rate limited", kid);
+                return keys;
+            }
+        }
+        debug!(target: "cache.get_keys_for_kid", "Kid {} not found, refreshing {}", kid, self.url);
+        *last_forced_refresh = Some(Instant::now());
+        self.fetch().await?.accepted
+    }
+
+    pub async fn invalidate(&self) {
+        *self.entry.write().await = None;
+    }
+
+    async fn fresh_key_set(&self) -> Option<KeySet> {
+        match &*self.entry.read().await {
+            Some(cached) if cached.is_fresh() => Some(cached.key_set.clone()),
+            _ => None,
+        }
+    }
+
+    #[throws(anyhow::Error)]
+    async fn fetch(&self) -> KeySet {
+        let started = Instant::now();
+        let span = info_span!("fetch_jwks", url = %self.url, outcome = field::Empty, error = field::Empty);
+        let key_set = self.download().instrument(span.clone()).await;
+        telemetry::record_outcome(&span, &key_set);
+        metrics::count(metrics::JWKS_FETCH);
+        metrics::timing(metrics::JWKS_FETCH_TIME, started.elapsed());
+        if key_set.is_err() {
+            metrics::count(metrics::JWKS_FETCH_ERROR);
+        }
+        // the token may well be fine, its keys are out of reach
+        key_set.context(AuthError::new(FailureCategory::Unavailable, format!("Could not fetch keys from {}", self.url)))?
+    }
+
+    #[throws(anyhow::Error)]
+    async fn download(&self) -> KeySet {
+        let response = self.client.get(&self.url).send().await?.error_for_status()?;
+        let ttl = ttl_from_headers(response.headers(), SystemTime::now()).unwrap_or(self.default_ttl);
+        let key_set = utils::parse_key_set(response.json().await?)?;
+        debug!(target: "cache.fetch", "Fetched {} keys ({} skipped), caching for {:?}", key_set.accepted.len(), key_set.rejected.len(), ttl);
+        *self.entry.write().await = Some(CachedJwks {
+            key_set: key_set.clone(),
+            expires_at: Instant::now() + ttl,
+        });
+        key_set
+    }
+}
+
+fn contains_kid(keys: &[JWK], kid: &str) -> bool {
+    keys.iter().any(|key| key.kid.as_deref() == Some(kid))
+}
+
+// Cache-Control takes precedence over Expires (RFC 7234, section 5.3).
+pub fn ttl_from_headers(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    if let Some(cache_control) = headers.get(CACHE_CONTROL).and_then(|value| value.to_str().ok()) {
+        for directive in cache_control.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            if directive == "no-cache" || directive == "no-store" {
+                return Some(Duration::from_secs(0));
+            }
+            if let Some(seconds) = directive.strip_prefix("max-age=") {
+                if let Ok(seconds) = seconds.trim_matches('"').parse::<u64>() {
+                    return Some(Duration::from_secs(seconds));
+                }
+            }
+        }
+    }
+    let expires = headers.get(EXPIRES)?.to_str().ok()?;
+    match httpdate::parse_http_date(expires) {
+        Ok(expires_at) => Some(expires_at.duration_since(now).unwrap_or_default()),
+        // an invalid Expires value means "already expired"
+        Err(_) => Some(Duration::from_secs(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_ttl_from_max_age() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=15780, stale-while-revalidate=15780"));
+        headers.insert(EXPIRES, HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"));
+        assert_eq!(ttl_from_headers(&headers, SystemTime::now()), Some(Duration::from_secs(15780)));
+    }
+
+    #[test]
+    fn test_ttl_from_no_store() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        assert_eq!(ttl_from_headers(&headers, SystemTime::now()), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_ttl_from_expires() {
+        let now = SystemTime::now();
+        let mut headers = HeaderMap::new();
+        let expires = httpdate::fmt_http_date(now + Duration::from_secs(120));
+        headers.insert(EXPIRES, HeaderValue::from_str(&expires).unwrap());
+        let ttl = ttl_from_headers(&headers, now).unwrap();
+        // http dates have a one second resolution
+        assert!(ttl > Duration::from_secs(118) && ttl <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_ttl_without_headers() {
+        assert_eq!(ttl_from_headers(&HeaderMap::new(), SystemTime::now()), None);
+    }
+}
@@ -0,0 +1,223 @@
+use std::path::Path;
+
+use anyhow::bail;
+use fehler::throws;
+use jsonwebtoken::DecodingKey;
+use log::debug;
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, BigNumContext},
+    hash::MessageDigest,
+    pkey::{Id, PKey, Public},
+    stack::Stack,
+    x509::{store::{X509Store, X509StoreBuilder}, X509StoreContext, X509},
+};
+
+use crate::{enums::KeyType, structs::JWK};
+
+// Certificates the x5c chains of the JWKS have to lead to. When configured,
+// the signing key is taken from the leaf certificate instead of n/e/x/y.
+pub struct TrustAnchors {
+    store: X509Store,
+}
+
+impl TrustAnchors {
+    #[throws(anyhow::Error)]
+    pub fn from_pem(pem: &[u8]) -> Self {
+        let certificates = X509::stack_from_pem(pem)?;
+        if certificates.is_empty() {
+            bail!("No certificates found in the trust anchor bundle")
+        }
+        let mut builder = X509StoreBuilder::new()?;
+        for certificate in certificates {
+            builder.add_cert(certificate)?;
+        }
+        debug!(target: "certificates.from_pem", "Loaded trust anchors");
+        Self { store: builder.build() }
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Self {
+        Self::from_pem(&std::fs::read(path)?)?
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn decoding_key(&self, jwk: &JWK) -> DecodingKey {
+        let leaf = self.verify_chain(jwk)?;
+        let public_key = leaf.public_key()?;
+        check_public_key(jwk, &public_key)?;
+        match public_key.id() {
+            Id::RSA => {
+                let rsa = public_key.rsa()?;
+                DecodingKey::from_rsa_raw_components(&rsa.n().to_vec(), &rsa.e().to_vec())
+            },
+            Id::EC => {
+                let (x, y) = ec_coordinates(&public_key)?;
+                DecodingKey::from_ec_components(&base64url(&x), &base64url(&y))?
+            },
+            Id::ED25519 => DecodingKey::from_ed_components(&base64url(&public_key.raw_public_key()?))?,
+            id => bail!("Unsupported certificate key type {:?}", id),
+        }
+    }
+
+    // Returns the leaf certificate once the whole chain has been checked
+    #[throws(anyhow::Error)]
+    fn verify_chain(&self, jwk: &JWK) -> X509 {
+        let chain = match &jwk.x5c {
+            Some(chain) if !chain.is_empty() => chain,
+            _ => bail!("Key {:?} has no x5c certificate chain", jwk.kid),
+        };
+        let mut certificates = vec![];
+        for certificate in chain {
+            // x5c uses plain base64, not base64url (RFC 7517, section 4.7)
+            certificates.push(X509::from_der(&base64::decode(certificate)?)?);
+        }
+        let leaf = certificates.remove(0);
+        let now = Asn1Time::days_from_now(0)?;
+        if leaf.not_before() > now {
+            bail!("Certificate for key {:?} is not valid yet", jwk.kid)
+        }
+        if leaf.not_after() < now {
+            bail!("Certificate for key {:?} has expired", jwk.kid)
+        }
+        if let Some(x5t_s256) = &jwk.x5t_s256 {
+            check_thumbprint(&leaf, MessageDigest::sha256(), x5t_s256)?;
+        }
+        if let Some(x5t) = &jwk.x5t {
+            check_thumbprint(&leaf, MessageDigest::sha1(), x5t)?;
+        }
+        let mut intermediates = Stack::new()?;
+        for certificate in certificates {
+            intermediates.push(certificate)?;
+        }
+        let mut context = X509StoreContext::new()?;
+        let verified = context.init(&self.store, &leaf, &intermediates, |context| {
+            Ok(if context.verify_cert()? { None } else { Some(context.error().error_string()) })
+        })?;
+        if let Some(error) = verified {
+            bail!("Certificate chain for key {:?} is not trusted: {}", jwk.kid, error)
+        }
+        leaf
+    }
+}
+
+#[throws(anyhow::Error)]
+fn check_thumbprint(leaf: &X509, digest: MessageDigest, expected: &str) {
+    if base64url(&leaf.digest(digest)?) != expected.trim_end_matches('=') {
+        bail!("Certificate thumbprint does not match {}", expected)
+    }
+}
+
+// The key in the certificate must be the one published alongside it
+#[throws(anyhow::Error)]
+fn check_public_key(jwk: &JWK, public_key: &PKey<Public>) {
+    let matches = match (&jwk.kty, public_key.id()) {
+        (KeyType::RSA, Id::RSA) => {
+            let rsa = public_key.rsa()?;
+            same_number(&jwk.n, rsa.n().to_vec())? && same_number(&jwk.e, rsa.e().to_vec())?
+        },
+        (KeyType::EC, Id::EC) => {
+            let (x, y) = ec_coordinates(public_key)?;
+            same_number(&jwk.x, x)? && same_number(&jwk.y, y)?
+        },
+        (KeyType::OKP, Id::ED25519) => same_number(&jwk.x, public_key.raw_public_key()?)?,
+        _ => false,
+    };
+    if !matches {
+        bail!("Certificate public key does not match key {:?}", jwk.kid)
+    }
+}
+
+#[throws(anyhow::Error)]
+fn same_number(published: &Option<String>, certificate: Vec<u8>) -> bool {
+    match published {
+        Some(published) => {
+            let published = base64::decode_config(published, base64::URL_SAFE_NO_PAD)?;
+            // compared as numbers, so leading zeroes do not matter
+            BigNum::from_slice(&published)? == BigNum::from_slice(&certificate)?
+        },
+        None => false,
+    }
+}
+
+#[throws(anyhow::Error)]
+fn ec_coordinates(public_key: &PKey<Public>) -> (Vec<u8>, Vec<u8>) {
+    let ec = public_key.ec_key()?;
+    let size = (ec.group().degree() as i32 + 7) / 8;
+    let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+    let mut context = BigNumContext::new()?;
+    ec.public_key().affine_coordinates(ec.group(), &mut x, &mut y, &mut context)?;
+    (x.to_vec_padded(size)?, y.to_vec_padded(size)?)
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{test_support::certificate, utils};
+    use openssl::{pkey::Private, rsa::Rsa};
+    use serde_json::json;
+
+    fn rsa_jwk(kid: &str, key: &PKey<Private>, chain: &[&X509]) -> JWK {
+        let rsa = key.rsa().unwrap();
+        serde_json::from_value(json!({
+            "kty": "RSA",
+            "kid": kid,
+            "n": base64url(&rsa.n().to_vec()),
+            "e": base64url(&rsa.e().to_vec()),
+            "x5c": chain.iter().map(|certificate| base64::encode(certificate.to_der().unwrap())).collect::<Vec<_>>(),
+        })).unwrap()
+    }
+
+    fn rsa_private_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_self_signed_auth0_certificates() {
+        let jwks = serde_json::from_str(&std::fs::read_to_string("tests/resources/auth0.keys.json").unwrap()).unwrap();
+        let keys = utils::parse_key_set(jwks).unwrap().accepted;
+        let leaf = X509::from_der(&base64::decode(&keys[0].x5c.as_ref().unwrap()[0]).unwrap()).unwrap();
+        let anchors = TrustAnchors::from_pem(&leaf.to_pem().unwrap()).unwrap();
+        assert!(anchors.decoding_key(&keys[0]).is_ok());
+        // signed by a certificate that is not in the bundle
+        assert!(anchors.decoding_key(&keys[1]).is_err());
+        // the modulus published in the jwks is not the one in the certificate
+        let mut tampered = keys[0].clone();
+        tampered.n = keys[1].n.clone();
+        assert!(anchors.decoding_key(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_chain_through_intermediate() {
+        let root_key = rsa_private_key();
+        let root = certificate("root", &root_key, None, true, (-1, 365));
+        let intermediate_key = rsa_private_key();
+        let intermediate = certificate("intermediate", &intermediate_key, Some((&root, &root_key)), true, (-1, 365));
+        let leaf_key = rsa_private_key();
+        let leaf = certificate("signing", &leaf_key, Some((&intermediate, &intermediate_key)), false, (-1, 30));
+        let anchors = TrustAnchors::from_pem(&root.to_pem().unwrap()).unwrap();
+        assert!(anchors.decoding_key(&rsa_jwk("leaf", &leaf_key, &[&leaf, &intermediate])).is_ok());
+        // the intermediate is needed to reach the root
+        assert!(anchors.decoding_key(&rsa_jwk("leaf", &leaf_key, &[&leaf])).is_err());
+        assert!(anchors.decoding_key(&rsa_jwk("leaf", &leaf_key, &[])).is_err());
+    }
+
+    #[test]
+    fn test_certificate_validity() {
+        let root_key = rsa_private_key();
+        let root = certificate("root", &root_key, None, true, (-10, 365));
+        let anchors = TrustAnchors::from_pem(&root.to_pem().unwrap()).unwrap();
+        let leaf_key = rsa_private_key();
+        let expired = certificate("signing", &leaf_key, Some((&root, &root_key)), false, (-10, -1));
+        let error = anchors.decoding_key(&rsa_jwk("leaf", &leaf_key, &[&expired])).err().unwrap();
+        assert!(error.to_string().contains("has expired"));
+        let not_yet_valid = certificate("signing", &leaf_key, Some((&root, &root_key)), false, (1, 10));
+        let error = anchors.decoding_key(&rsa_jwk("leaf", &leaf_key, &[&not_yet_valid])).err().unwrap();
+        assert!(error.to_string().contains("not valid yet"));
+    }
+}
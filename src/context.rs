@@ -0,0 +1,251 @@
+use std::path::Path;
+
+use anyhow::bail;
+use fehler::throws;
+use log::{debug, warn};
+use serde_json::{Map, Value};
+
+use crate::{
+    enums::{ContextEncoding, FailureCategory},
+    structs::{AuthError, ContextClaim, ContextConfig, DynamicClaims, default_max_context_size, default_max_context_value_length},
+};
+
+// Set by the authorizer itself
+pub const RESERVED_KEYS: [&str; 1] = ["audience"];
+
+// Builds the context API Gateway hands to the integration ($context.authorizer.<key>)
+// from the claims of a valid token
+#[derive(Debug)]
+pub struct ContextMapping {
+    config: ContextConfig,
+}
+
+impl Default for ContextMapping {
+    // sub and user_id, what the context always held before it could be configured
+    fn default() -> Self {
+        let claim = |name: &str| ContextClaim { claim: name.to_string(), key: None, encoding: ContextEncoding::default() };
+        Self {
+            config: ContextConfig {
+                claims: vec![claim("sub"), claim("user_id")],
+                max_value_length: default_max_context_value_length(),
+                max_size: default_max_context_size(),
+            },
+        }
+    }
+}
+
+impl ContextMapping {
+    #[throws(anyhow::Error)]
+    pub fn new(config: ContextConfig) -> Self {
+        let mut keys = vec![];
+        for rule in &config.claims {
+            let key = key(rule);
+            if rule.claim.is_empty() || rule.claim == "/" {
+                bail!("Context claim must be a claim name or a JSON pointer")
+            }
+            if !is_valid_key(&key) {
+                bail!("Context key {} of claim {} must be made of letters, digits, _, -, . and :", key, rule.claim)
+            }
+            if RESERVED_KEYS.contains(&key.as_str()) {
+                bail!("Context key {} is set by the authorizer", key)
+            }
+            if keys.contains(&key) {
+                bail!("Context key {} is mapped more than once", key)
+            }
+            keys.push(key);
+        }
+        Self { config }
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_json(json: &str) -> Self {
+        Self::new(serde_json::from_str(json)?)?
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
+        Self::from_json(&std::fs::read_to_string(path)?)?
+    }
+
+    // Adds the mapped claims to context, missing and null claims are left out.
+    // A context API Gateway would not accept refuses the token.
+    #[throws(anyhow::Error)]
+    pub fn apply(&self, claims: &DynamicClaims, namespace: Option<&str>, mut context: Map<String, Value>) -> Map<String, Value> {
+        for rule in &self.config.claims {
+            if let Some(value) = lookup(claims, &rule.claim, namespace) {
+                encode(key(rule), value, rule.encoding, &mut context);
+            }
+        }
+        let max_value_length = self.config.max_value_length;
+        context.retain(|key, value| match value {
+            Value::String(value) if value.len() > max_value_length => {
+                warn!(target: "context.apply", "Context value of {} is longer than {} bytes, left out", key, max_value_length);
+                false
+            },
+            _ => true,
+        });
+        check(&context, self.config.max_size)?;
+        debug!(target: "context.apply", "Context keys: {:?}", context.keys());
+        context
+    }
+}
+
+// What API Gateway accepts: a flat object of strings, numbers and booleans
+#[throws(anyhow::Error)]
+pub fn check(context: &Map<String, Value>, max_size: usize) {
+    for (key, value) in context {
+        if !is_valid_key(key) {
+            Err(AuthError::new(FailureCategory::Invalid, format!("Context key {} must be made of letters, digits, _, -, . and :", key)))?
+        }
+        if !is_scalar(value) {
+            Err(AuthError::new(FailureCategory::Invalid, format!("Context value of {} must be a string, a number or a boolean", key)))?
+        }
+    }
+    let size = serde_json::to_string(context)?.len();
+    if size > max_size {
+        Err(AuthError::new(FailureCategory::Invalid, format!("Context takes {} bytes, more than {}", size, max_size)))?
+    }
+}
+
+fn key(rule: &ContextClaim) -> String {
+    match (&rule.key, rule.claim.strip_prefix('/')) {
+        (Some(key), _) => key.clone(),
+        (None, Some(pointer)) => unescape(pointer.rsplit('/').next().unwrap_or_default()),
+        (None, None) => rule.claim.clone(),
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+}
+
+fn is_scalar(value: &Value) -> bool {
+    matches!(value, Value::String(_) | Value::Number(_) | Value::Bool(_))
+}
+
+// ~1 and ~0 stand for / and ~ in JSON pointers
+fn unescape(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+// A pointer starts with a claim, which may be namespaced like any other
+fn lookup<'a>(claims: &'a DynamicClaims, claim: &str, namespace: Option<&str>) -> Option<&'a Value> {
+    let value = match claim.strip_prefix('/') {
+        Some(pointer) => {
+            let (name, rest) = pointer.find('/').map_or((pointer, ""), |index| pointer.split_at(index));
+            claims.claim(&unescape(name), namespace)?.pointer(rest)?
+        },
+        None => claims.claim(claim, namespace)?,
+    };
+    Some(value).filter(|value| !value.is_null())
+}
+
+fn encode(key: String, value: &Value, encoding: ContextEncoding, context: &mut Map<String, Value>) {
+    match (value, encoding) {
+        (Value::Null, _) => {},
+        (Value::Array(values), ContextEncoding::Flatten) if values.iter().all(is_scalar) => {
+            let values: Vec<String> = values.iter().map(|value| value.as_str().map_or_else(|| value.to_string(), str::to_string)).collect();
+            context.insert(key, Value::String(values.join(",")));
+        },
+        (Value::Array(values), ContextEncoding::Flatten) => {
+            for (index, value) in values.iter().enumerate() {
+                encode(format!("{}.{}", key, index), value, encoding, context);
+            }
+        },
+        (Value::Object(members), ContextEncoding::Flatten) => {
+            for (name, value) in members {
+                encode(format!("{}.{}", key, name), value, encoding, context);
+            }
+        },
+        (Value::Array(_) | Value::Object(_), ContextEncoding::Json) => {
+            context.insert(key, Value::String(value.to_string()));
+        },
+        (value, _) => {
+            context.insert(key, value.clone());
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    const NAMESPACE: &str = "https://boto.io/claims/";
+
+    fn claims() -> DynamicClaims {
+        serde_json::from_value(json!({
+            "sub": "auth0|123456",
+            "email_verified": true,
+            "https://boto.io/claims/user_id": 42,
+            "https://boto.io/claims/tenant": { "id": "t-1", "plan": "pro", "limits": { "seats": 5 } },
+            "groups": ["admin", "dev"],
+            "orgs": [{ "id": "o-1" }, { "id": "o-2" }],
+            "address": { "country": "ES" },
+            "nickname": null,
+        })).unwrap()
+    }
+
+    fn apply(mapping: serde_json::Value) -> anyhow::Result<Map<String, Value>> {
+        ContextMapping::from_json(&mapping.to_string())?.apply(&claims(), Some(NAMESPACE), Map::new())
+    }
+
+    #[test]
+    fn test_default_mapping() {
+        let context = ContextMapping::default().apply(&claims(), Some(NAMESPACE), Map::new()).unwrap();
+        assert_eq!(Value::Object(context), json!({ "sub": "auth0|123456", "user_id": 42 }));
+    }
+
+    #[test]
+    fn test_mapping() {
+        let context = apply(json!({ "claims": [
+            { "claim": "sub", "key": "principal" },
+            { "claim": "email_verified" },
+            { "claim": "/address/country" },
+            { "claim": "/tenant/plan", "key": "plan" },
+            { "claim": "tenant" },
+            { "claim": "groups" },
+            { "claim": "orgs" },
+            { "claim": "groups", "key": "group_list", "encoding": "json" },
+            { "claim": "address", "encoding": "json" },
+            { "claim": "nickname" },
+            { "claim": "/address/city" },
+        ]})).unwrap();
+        assert_eq!(Value::Object(context), json!({
+            "principal": "auth0|123456",
+            "email_verified": true,
+            "country": "ES",
+            "plan": "pro",
+            "tenant.id": "t-1",
+            "tenant.plan": "pro",
+            "tenant.limits.seats": 5,
+            "groups": "admin,dev",
+            "orgs.0.id": "o-1",
+            "orgs.1.id": "o-2",
+            "group_list": "[\"admin\",\"dev\"]",
+            "address": "{\"country\":\"ES\"}",
+        }));
+    }
+
+    #[test]
+    fn test_limits() {
+        let context = apply(json!({ "claims": [{ "claim": "sub" }, { "claim": "groups" }], "max_value_length": 10 })).unwrap();
+        assert_eq!(Value::Object(context), json!({ "groups": "admin,dev" }));
+        let error = apply(json!({ "claims": [{ "claim": "tenant" }], "max_size": 32 })).unwrap_err();
+        assert_eq!(error.downcast_ref::<AuthError>().unwrap().category, FailureCategory::Invalid);
+        let mut context = Map::new();
+        context.insert("scopes".to_string(), json!(["read"]));
+        assert!(check(&context, 4096).is_err());
+    }
+
+    #[test]
+    fn test_invalid_mapping() {
+        assert!(apply(json!({ "claims": [{ "claim": "cognito:groups" }] })).is_ok());
+        assert!(apply(json!({ "claims": [{ "claim": "sub", "key": "principal id" }] })).is_err());
+        assert!(apply(json!({ "claims": [{ "claim": "aud", "key": "audience" }] })).is_err());
+        assert!(apply(json!({ "claims": [{ "claim": "sub" }, { "claim": "/sub" }] })).is_err());
+        assert!(apply(json!({ "claims": [{ "claim": "/" }] })).is_err());
+        assert!(apply(json!({ "claims": [{ "claim": "sub", "encoding": "yaml" }] })).is_err());
+    }
+}
@@ -1,8 +1,23 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use fehler::throws;
+use jsonwebtoken::Algorithm;
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{
+    structs::{
+        APIGatewayCustomAuthorizerRequest, APIGatewayCustomAuthorizerResponse, APIGatewayRequestAuthorizerRequest, AuthError,
+        HttpApiAuthorizerRequest, HttpApiSimpleResponse, RequestValues,
+    },
+    utils,
+};
 use strum_macros::EnumString;
 use strum_macros::Display;
 
-#[derive(Debug, Serialize, Deserialize)]
+// Allow sorts before Deny
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Effect {
     Allow,
     Deny,
@@ -15,7 +30,74 @@ pub enum StringOrArray {
     StrArray(Vec<String>),   
 }
 
-#[derive(Serialize, Deserialize, EnumString, Display)]
+impl StringOrArray {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            StringOrArray::Str(value) => vec![value.clone()],
+            StringOrArray::StrArray(values) => values.clone(),
+        }
+    }
+}
+
+// How the configured audiences are matched against the aud claim
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AudienceMatch {
+    // at least one configured audience is in the token
+    #[default]
+    Any,
+    // every configured audience is in the token
+    All,
+}
+
+// Why a request was refused, see failures::classify
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum FailureCategory {
+    // no token in the request
+    MissingToken,
+    // not a JWT, bad base64 or JSON, unusable header
+    Malformed,
+    // exp (or nbf) out of range
+    Expired,
+    // signature, algorithm or key did not check out
+    InvalidSignature,
+    // anything else wrong with the token: issuer, audience, claims...
+    Invalid,
+    // valid token, but it grants none of the configured routes
+    InsufficientScope,
+    // valid token of a user who may not call the API
+    BlockedUser,
+    // the keys or the user directory could not be read, nothing is known to be wrong with the token
+    Unavailable,
+}
+
+impl FailureCategory {
+    // The whole stage is denied, unless the authorizer could not tell
+    pub fn default_action(&self) -> FailureAction {
+        match self {
+            FailureCategory::Unavailable => FailureAction::Error,
+            _ => FailureAction::Deny,
+        }
+    }
+}
+
+// How the authorizer answers a refused token
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum FailureAction {
+    // a policy denying every method and resource of the stage, API Gateway answers 403
+    Deny,
+    // the Unauthorized error, API Gateway answers 401
+    Unauthorized,
+    // the error itself, API Gateway answers 500 and caches nothing
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, EnumString, Display)]
 pub enum HttpMethod {
     #[serde(rename = "GET")]
     GET,
@@ -35,12 +117,214 @@ pub enum HttpMethod {
     ALL,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub enum KeyType { 
-    RSA
+    RSA,
+    EC,
+    // Octet key pair (RFC 8037), used by EdDSA
+    OKP,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub enum KeyAlgorithm {
-    RS256
-}
\ No newline at end of file
+    RS256,
+    RS384,
+    RS512,
+    PS256,
+    PS384,
+    PS512,
+    ES256,
+    ES384,
+    EdDSA,
+}
+
+impl KeyAlgorithm {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            KeyAlgorithm::RS256 => Algorithm::RS256,
+            KeyAlgorithm::RS384 => Algorithm::RS384,
+            KeyAlgorithm::RS512 => Algorithm::RS512,
+            KeyAlgorithm::PS256 => Algorithm::PS256,
+            KeyAlgorithm::PS384 => Algorithm::PS384,
+            KeyAlgorithm::PS512 => Algorithm::PS512,
+            KeyAlgorithm::ES256 => Algorithm::ES256,
+            KeyAlgorithm::ES384 => Algorithm::ES384,
+            KeyAlgorithm::EdDSA => Algorithm::EdDSA,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub enum EllipticCurve {
+    #[serde(rename = "P-256")]
+    P256,
+    #[serde(rename = "P-384")]
+    P384,
+    Ed25519,
+}
+// Where REQUEST authorizers look for the token, e.g. header:Authorization,
+// cookie:access_token or query:access_token
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenSource {
+    Header(String),
+    Cookie(String),
+    Query(String),
+}
+
+impl FromStr for TokenSource {
+    type Err = anyhow::Error;
+
+    #[throws(anyhow::Error)]
+    fn from_str(source: &str) -> Self {
+        match source.trim().split_once(':') {
+            Some(("header", name)) if !name.is_empty() => TokenSource::Header(name.to_string()),
+            Some(("cookie", name)) if !name.is_empty() => TokenSource::Cookie(name.to_string()),
+            Some(("query", name)) if !name.is_empty() => TokenSource::Query(name.to_string()),
+            _ => bail!("Token source {} must be header:<name>, cookie:<name> or query:<name>", source),
+        }
+    }
+}
+
+// The authorizer events API Gateway sends, told apart by their version and type
+#[derive(Debug)]
+pub enum AuthorizerEvent {
+    Token(APIGatewayCustomAuthorizerRequest),
+    Request(Box<APIGatewayRequestAuthorizerRequest>),
+    // HTTP API, payload format 2.0
+    HttpApi(Box<HttpApiAuthorizerRequest>),
+}
+
+impl AuthorizerEvent {
+    #[throws(anyhow::Error)]
+    pub fn from_value(event: Value) -> Self {
+        if event.get("version").and_then(Value::as_str) == Some("2.0") {
+            return AuthorizerEvent::HttpApi(Box::new(serde_json::from_value(event)?));
+        }
+        match event.get("type").and_then(Value::as_str) {
+            Some("TOKEN") => AuthorizerEvent::Token(serde_json::from_value(event)?),
+            Some("REQUEST") => AuthorizerEvent::Request(Box::new(serde_json::from_value(event)?)),
+            other => bail!("Unsupported authorizer event type {:?}", other),
+        }
+    }
+
+    // methodArn, or routeArn for HTTP APIs
+    pub fn method_arn(&self) -> &str {
+        match self {
+            AuthorizerEvent::Token(event) => &event.method_arn,
+            AuthorizerEvent::Request(event) => &event.method_arn,
+            AuthorizerEvent::HttpApi(event) => &event.route_arn,
+        }
+    }
+
+    // API Gateway request id, TOKEN events do not carry one
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            AuthorizerEvent::Token(_) => None,
+            AuthorizerEvent::Request(event) => event.request_context.as_ref()?.request_id.as_deref(),
+            AuthorizerEvent::HttpApi(event) => event.request_context.as_ref()?.request_id.as_deref(),
+        }
+    }
+
+    // Method and path of the call being authorized, unknown to TOKEN authorizers
+    pub fn route(&self) -> Option<(HttpMethod, &str)> {
+        let (method, path) = match self {
+            AuthorizerEvent::Token(_) => return None,
+            AuthorizerEvent::Request(event) => (event.http_method.as_deref()?, event.path.as_deref()?),
+            AuthorizerEvent::HttpApi(event) => {
+                let http = event.request_context.as_ref()?.http.as_ref()?;
+                (http.method.as_deref()?, event.raw_path.as_deref().or(http.path.as_deref())?)
+            },
+        };
+        Some((HttpMethod::from_str(method).ok()?, path))
+    }
+
+    // The first token found in the sources, in order. HTTP APIs fall back to
+    // the identity source.
+    #[throws(anyhow::Error)]
+    pub fn token(&self, sources: &[TokenSource]) -> String {
+        let token = match self {
+            AuthorizerEvent::Token(event) => Some(utils::bearer_token(&event.authorization_token)?),
+            AuthorizerEvent::Request(event) => event.token(sources),
+            AuthorizerEvent::HttpApi(event) => event.token(sources).or_else(|| {
+                event.identity_source.iter().flatten().find_map(|value| utils::bearer_token(value).ok())
+            }),
+        };
+        match token {
+            Some(token) => token,
+            None => Err(AuthError::new(FailureCategory::MissingToken, "No token in the request"))?,
+        }
+    }
+}
+
+// Outcome of an authorization, as logged
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Decision {
+    Allow,
+    // 403
+    Deny,
+    // 401
+    Unauthorized,
+    // 500
+    Error,
+}
+
+// Units of the metrics, as named by CloudWatch
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum MetricUnit {
+    Count,
+    Milliseconds,
+}
+
+// json for the log pipeline, text for humans
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+// Response of HTTP API authorizers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum HttpApiResponseMode {
+    // { isAuthorized, context }, needs simple responses enabled on the authorizer
+    Simple,
+    // a policy, like REST APIs
+    #[default]
+    Iam,
+}
+
+// How arrays and objects go into the authorizer context, which only takes strings, numbers and booleans
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ContextEncoding {
+    // lists of values joined with commas, objects spread over key.member entries
+    #[default]
+    Flatten,
+    // arrays and objects as JSON strings
+    Json,
+}
+
+// What happens to valid tokens when the user directory cannot be reached
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum DirectoryFailureMode {
+    // let through, as if the user was not blocked
+    Open,
+    // refused as invalid
+    #[default]
+    Closed,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum AuthorizerResponse {
+    Policy(APIGatewayCustomAuthorizerResponse),
+    Simple(HttpApiSimpleResponse),
+}
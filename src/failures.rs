@@ -0,0 +1,248 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use fehler::throws;
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use log::debug;
+
+use serde_json::{json, Value};
+
+use crate::{enums::{FailureAction, FailureCategory}, structs::{APIGatewayCustomAuthorizerPolicy, APIGatewayCustomAuthorizerResponse, APIGatewayPolicyBuilder, AuthError, HttpApiSimpleResponse}};
+
+// The only error message API Gateway turns into a 401
+pub const UNAUTHORIZED: &str = "Unauthorized";
+
+// The client has to authenticate (again): its token is missing or unusable
+pub const AUTHENTICATION_FAILURES: [FailureCategory; 5] = [
+    FailureCategory::MissingToken,
+    FailureCategory::Malformed,
+    FailureCategory::Expired,
+    FailureCategory::InvalidSignature,
+    FailureCategory::Invalid,
+];
+
+// What to answer for each category of refused request, see
+// FailureCategory::default_action for the defaults
+#[derive(Debug, Default)]
+pub struct FailurePolicy {
+    default: Option<FailureAction>,
+    actions: HashMap<FailureCategory, FailureAction>,
+}
+
+impl FailurePolicy {
+    // The same answer for every category, unless overridden
+    pub fn new(default: FailureAction) -> Self {
+        Self { default: Some(default), actions: HashMap::new() }
+    }
+
+    // 401 when the client has to authenticate (again), the defaults otherwise
+    pub fn by_category() -> Self {
+        AUTHENTICATION_FAILURES.iter().fold(Self::default(), |policy, category| policy.with_action(*category, FailureAction::Unauthorized))
+    }
+
+    pub fn with_action(mut self, category: FailureCategory, action: FailureAction) -> Self {
+        self.actions.insert(category, action);
+        self
+    }
+
+    // Parses overrides such as "expired=unauthorized,malformed=deny"
+    #[throws(anyhow::Error)]
+    pub fn with_actions(mut self, actions: &str) -> Self {
+        for action in actions.split(',').map(str::trim).filter(|action| !action.is_empty()) {
+            let (category, action) = match action.split_once('=') {
+                Some((category, action)) => (category.trim(), action.trim()),
+                None => bail!("Expected category=action, got {}", action),
+            };
+            let category = match FailureCategory::from_str(category) {
+                Ok(category) => category,
+                Err(_) => bail!("Unknown failure category {}", category),
+            };
+            let action = match FailureAction::from_str(action) {
+                Ok(action) => action,
+                Err(_) => bail!("Unknown failure action {}", action),
+            };
+            self = self.with_action(category, action);
+        }
+        self
+    }
+
+    pub fn action(&self, category: FailureCategory) -> FailureAction {
+        match self.actions.get(&category) {
+            Some(action) => *action,
+            None => self.default.unwrap_or_else(|| category.default_action()),
+        }
+    }
+
+    // The Unauthorized error for a 401, a response denying everything for a 403,
+    // any other error for a 500
+    #[throws(anyhow::Error)]
+    pub fn respond(&self, error: &anyhow::Error, builder: APIGatewayPolicyBuilder) -> APIGatewayCustomAuthorizerResponse {
+        match self.action(classify(error)) {
+            FailureAction::Unauthorized => bail!(UNAUTHORIZED),
+            FailureAction::Error => Err(anyhow!("{:#}", error))?,
+            FailureAction::Deny => deny_response(error, builder)?,
+        }
+    }
+}
+
+// HTTP APIs answer 500 to authorizer errors, so they always get a 403
+#[throws(anyhow::Error)]
+pub fn deny_response(error: &anyhow::Error, builder: APIGatewayPolicyBuilder) -> APIGatewayCustomAuthorizerResponse {
+    APIGatewayCustomAuthorizerResponse {
+        principal_id: "user".to_string(),
+        policy_document: deny_all(builder)?,
+        context: failure_context(error),
+    }
+}
+
+pub fn simple_response(error: &anyhow::Error) -> HttpApiSimpleResponse {
+    HttpApiSimpleResponse {
+        is_authorized: false,
+        context: failure_context(error),
+    }
+}
+
+fn failure_context(error: &anyhow::Error) -> Value {
+    json!({
+        "messageDescription": format!("Error validating token: {}", error.root_cause()),
+        "messageType": "Access Denied".to_string(),
+        "failureCategory": classify(error).to_string()
+    })
+}
+
+pub fn classify(error: &anyhow::Error) -> FailureCategory {
+    if let Some(error) = error.downcast_ref::<AuthError>() {
+        return error.category;
+    }
+    let category = match error.downcast_ref::<JwtError>().map(JwtError::kind) {
+        Some(ErrorKind::ExpiredSignature | ErrorKind::ImmatureSignature) => FailureCategory::Expired,
+        Some(ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm | ErrorKind::InvalidRsaKey(_) | ErrorKind::InvalidEcdsaKey
+            | ErrorKind::InvalidKeyFormat | ErrorKind::Crypto(_)) => FailureCategory::InvalidSignature,
+        Some(ErrorKind::InvalidToken | ErrorKind::InvalidAlgorithmName | ErrorKind::Base64(_) | ErrorKind::Json(_)
+            | ErrorKind::Utf8(_)) => FailureCategory::Malformed,
+        Some(_) => FailureCategory::Invalid,
+        None if error.is::<base64::DecodeError>() || error.is::<serde_json::Error>() => FailureCategory::Malformed,
+        None => FailureCategory::Invalid,
+    };
+    debug!(target: "failures.classify", "{} is {}", error, category);
+    category
+}
+
+// Denies every method and resource of the stage
+#[throws(anyhow::Error)]
+pub fn deny_all(builder: APIGatewayPolicyBuilder) -> APIGatewayCustomAuthorizerPolicy {
+    builder.deny_all_methods().build()?
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{auth::Auth, test_support::*, utils};
+    use jsonwebtoken::Algorithm;
+    use serde_json::json;
+
+    #[test]
+    fn test_classify() {
+        let key = TestKey::rsa("rs256", Algorithm::RS256);
+        let mut auth = Auth::new(vec![TEST_AUDIENCE.to_string()], TEST_ISSUER.to_string(), vec![key.jwk()]);
+        let mut claims = test_claims();
+        claims["exp"] = json!(now() - 60);
+        assert_eq!(classify(&auth.validate_token(&key.sign(&claims)).unwrap_err()), FailureCategory::Expired);
+        let mut claims = test_claims();
+        claims["nbf"] = json!(now() + 600);
+        assert_eq!(classify(&auth.validate_token(&key.sign(&claims)).unwrap_err()), FailureCategory::Expired);
+        // signed with another key published under the same kid
+        let other = TestKey::rsa("rs256", Algorithm::RS256);
+        assert_eq!(classify(&auth.validate_token(&other.sign(&test_claims())).unwrap_err()), FailureCategory::InvalidSignature);
+        let es256 = TestKey::ec("rs256", Algorithm::ES256);
+        assert_eq!(classify(&auth.validate_token(&es256.sign(&test_claims())).unwrap_err()), FailureCategory::InvalidSignature);
+        assert_eq!(classify(&auth.validate_token("not a token").unwrap_err()), FailureCategory::Malformed);
+        assert_eq!(classify(&auth.validate_token("e30.e30.c2ln!").unwrap_err()), FailureCategory::Malformed);
+        assert_eq!(classify(&utils::unverified_issuer("not a token").unwrap_err()), FailureCategory::Malformed);
+        let mut claims = test_claims();
+        claims["aud"] = json!("https://other.example.com");
+        assert_eq!(classify(&auth.validate_token(&key.sign(&claims)).unwrap_err()), FailureCategory::Invalid);
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        for category in [FailureCategory::MissingToken, FailureCategory::InsufficientScope, FailureCategory::BlockedUser] {
+            assert_eq!(classify(&AuthError::new(category, "refused").into()), category);
+        }
+        assert_eq!(classify(&utils::bearer_token("Bearer ").unwrap_err()), FailureCategory::MissingToken);
+        // the category survives added context
+        let error = anyhow::Error::from(AuthError::new(FailureCategory::BlockedUser, "blocked")).context("looking up user");
+        assert_eq!(classify(&error), FailureCategory::BlockedUser);
+    }
+
+    #[test]
+    fn test_default_actions() {
+        let policy = FailurePolicy::default();
+        for category in AUTHENTICATION_FAILURES.iter().chain(&[FailureCategory::InsufficientScope, FailureCategory::BlockedUser]) {
+            assert_eq!(policy.action(*category), FailureAction::Deny, "{}", category);
+        }
+        assert_eq!(policy.action(FailureCategory::Unavailable), FailureAction::Error);
+    }
+
+    #[test]
+    fn test_actions_by_category() {
+        let policy = FailurePolicy::by_category();
+        for category in AUTHENTICATION_FAILURES {
+            assert_eq!(policy.action(category), FailureAction::Unauthorized, "{}", category);
+        }
+        for category in [FailureCategory::InsufficientScope, FailureCategory::BlockedUser] {
+            assert_eq!(policy.action(category), FailureAction::Deny, "{}", category);
+        }
+        assert_eq!(policy.action(FailureCategory::Unavailable), FailureAction::Error);
+    }
+
+    #[test]
+    fn test_actions() {
+        let policy = FailurePolicy::new(FailureAction::Deny).with_actions("expired=unauthorized, missing_token = unauthorized").unwrap();
+        assert_eq!(policy.action(FailureCategory::Expired), FailureAction::Unauthorized);
+        assert_eq!(policy.action(FailureCategory::MissingToken), FailureAction::Unauthorized);
+        assert_eq!(policy.action(FailureCategory::InvalidSignature), FailureAction::Deny);
+        let policy = FailurePolicy::by_category().with_action(FailureCategory::Invalid, FailureAction::Deny);
+        assert_eq!(policy.action(FailureCategory::Expired), FailureAction::Unauthorized);
+        assert_eq!(policy.action(FailureCategory::Invalid), FailureAction::Deny);
+        let policy = FailurePolicy::default().with_actions("expired=unauthorized").unwrap();
+        assert_eq!(policy.action(FailureCategory::Expired), FailureAction::Unauthorized);
+        assert_eq!(policy.action(FailureCategory::Malformed), FailureAction::Deny);
+        assert!(FailurePolicy::default().with_actions("expired").is_err());
+        assert!(FailurePolicy::default().with_actions("revoked=deny").is_err());
+        assert!(FailurePolicy::default().with_actions("expired=allow").is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let builder = || APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod");
+        let policy = FailurePolicy::default();
+        let expired = AuthError::new(FailureCategory::Expired, "ExpiredSignature").into();
+        assert_eq!(policy.respond(&expired, builder()).unwrap().context["failureCategory"], "expired");
+        assert_eq!(FailurePolicy::by_category().respond(&expired, builder()).unwrap_err().to_string(), UNAUTHORIZED);
+        let blocked = AuthError::new(FailureCategory::BlockedUser, "User is blocked").into();
+        let response = policy.respond(&blocked, builder()).unwrap();
+        assert_eq!(response.context["failureCategory"], "blocked_user");
+        assert_eq!(response.context["messageDescription"], "Error validating token: User is blocked");
+        assert_eq!(response.policy_document.Statement[0].Effect, crate::enums::Effect::Deny);
+        // anything but Unauthorized, API Gateway answers 500
+        let unavailable = anyhow::Error::msg("Connection refused").context(AuthError::new(FailureCategory::Unavailable, "User directory is unavailable"));
+        assert_eq!(classify(&unavailable), FailureCategory::Unavailable);
+        assert_eq!(policy.respond(&unavailable, builder()).unwrap_err().to_string(), "User directory is unavailable: Connection refused");
+        let simple = simple_response(&expired);
+        assert!(!simple.is_authorized);
+        assert_eq!(simple.context["failureCategory"], "expired");
+    }
+
+    #[test]
+    fn test_deny_all() {
+        let policy = deny_all(APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod")).unwrap();
+        let statements = serde_json::to_value(&policy.Statement).unwrap();
+        assert_eq!(statements, json!([{
+            "Action": ["execute-api:Invoke"],
+            "Effect": "Deny",
+            "Resource": ["arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/*/*"],
+        }]));
+    }
+}
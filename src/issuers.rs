@@ -0,0 +1,177 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::bail;
+use fehler::throws;
+use jsonwebtoken::{Algorithm, decode_header};
+use log::debug;
+
+use crate::{auth::Auth, cache::JwksCache, certificates::TrustAnchors, enums::AudienceMatch, structs::{default_principal_claim, IssuerConfig, TimeValidation}, utils};
+
+// Everything needed to verify the tokens of one issuer
+pub struct IssuerProfile {
+    pub issuer: String,
+    pub audiences: Vec<String>,
+    pub audience_match: AudienceMatch,
+    pub algorithms: Vec<Algorithm>,
+    pub required_claims: Vec<String>,
+    pub time_validation: TimeValidation,
+    pub claims_namespace: Option<String>,
+    pub principal_claim: String,
+    pub jwks_cache: JwksCache,
+    pub trust_anchors: Option<Arc<TrustAnchors>>,
+}
+
+impl IssuerProfile {
+    pub fn new(issuer: String, audiences: Vec<String>, jwks_cache: JwksCache) -> Self {
+        Self {
+            issuer,
+            audiences,
+            audience_match: AudienceMatch::Any,
+            algorithms: vec![Algorithm::RS256],
+            required_claims: vec![],
+            time_validation: TimeValidation::default(),
+            claims_namespace: None,
+            principal_claim: default_principal_claim(),
+            jwks_cache,
+            trust_anchors: None,
+        }
+    }
+
+    // An explicit keys repo wins over the one advertised by the issuer
+    #[throws(anyhow::Error)]
+    pub async fn from_config(config: &IssuerConfig, keys_ttl: Duration, min_refresh_interval: Duration) -> Self {
+        let (keys_repo, algorithms) = match &config.keys_repo {
+            Some(keys_repo) => (keys_repo.clone(), vec![Algorithm::RS256]),
+            None => {
+                let configuration = utils::get_openid_configuration(&config.issuer).await?;
+                debug!(target: "issuers.from_config", "Discovered jwks_uri {} for {}", configuration.jwks_uri, config.issuer);
+                let algorithms = configuration.signing_algorithms()?;
+                (configuration.jwks_uri, algorithms)
+            }
+        };
+        let algorithms = match &config.algorithms {
+            Some(allowed) => utils::parse_algorithms(allowed)?,
+            None => algorithms,
+        };
+        let jwks_cache = JwksCache::new(keys_repo, keys_ttl).with_min_refresh_interval(min_refresh_interval);
+        let mut profile = Self::new(config.issuer.clone(), config.audience.to_vec(), jwks_cache)
+            .with_audience_match(config.audience_match)
+            .with_algorithms(algorithms)
+            .with_required_claims(config.required_claims.clone())
+            .with_time_validation(config.time_validation.clone())
+            .with_claims_namespace(config.claims_namespace.clone())
+            .with_principal_claim(config.principal_claim.clone());
+        if let Some(path) = &config.x5c_trust_anchors {
+            profile = profile.with_trust_anchors(Arc::new(TrustAnchors::from_pem_file(path)?));
+        }
+        profile
+    }
+
+    pub fn with_audience_match(mut self, audience_match: AudienceMatch) -> Self {
+        self.audience_match = audience_match;
+        self
+    }
+
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub fn with_required_claims(mut self, required_claims: Vec<String>) -> Self {
+        self.required_claims = required_claims;
+        self
+    }
+
+    pub fn with_time_validation(mut self, time_validation: TimeValidation) -> Self {
+        self.time_validation = time_validation;
+        self
+    }
+
+    pub fn with_claims_namespace(mut self, claims_namespace: Option<String>) -> Self {
+        self.claims_namespace = claims_namespace;
+        self
+    }
+
+    pub fn with_principal_claim(mut self, principal_claim: String) -> Self {
+        self.principal_claim = principal_claim;
+        self
+    }
+
+    pub fn with_trust_anchors(mut self, trust_anchors: Arc<TrustAnchors>) -> Self {
+        self.trust_anchors = Some(trust_anchors);
+        self
+    }
+
+    // An unknown kid may mean the IdP rotated its keys, so the cache gets a chance to refresh
+    #[throws(anyhow::Error)]
+    pub async fn auth(&self, kid: Option<&str>) -> Auth {
+        let keys = match kid {
+            Some(kid) => self.jwks_cache.get_keys_for_kid(kid).await?,
+            None => self.jwks_cache.get_keys().await?,
+        };
+        let mut auth = Auth::new(self.audiences.clone(), self.issuer.clone(), keys)
+            .with_audience_match(self.audience_match)
+            .with_algorithms(self.algorithms.clone())
+            .with_required_claims(self.required_claims.clone())
+            .with_time_validation(self.time_validation.clone())
+            .with_claims_namespace(self.claims_namespace.clone())
+            .with_principal_claim(self.principal_claim.clone());
+        if let Some(trust_anchors) = &self.trust_anchors {
+            auth = auth.with_trust_anchors(trust_anchors.clone());
+        }
+        auth
+    }
+}
+
+// Trusted issuers, keyed by their exact iss value
+#[derive(Default)]
+pub struct IssuerRegistry {
+    profiles: HashMap<String, IssuerProfile>,
+}
+
+impl IssuerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[throws(anyhow::Error)]
+    pub async fn from_configs(configs: &[IssuerConfig], keys_ttl: Duration, min_refresh_interval: Duration) -> Self {
+        let mut registry = Self::new();
+        for config in configs {
+            registry.register(IssuerProfile::from_config(config, keys_ttl, min_refresh_interval).await?);
+        }
+        registry
+    }
+
+    pub fn register(&mut self, profile: IssuerProfile) {
+        debug!(target: "issuers.register", "Registering issuer {}", profile.issuer);
+        self.profiles.insert(profile.issuer.clone(), profile);
+    }
+
+    pub fn issuers(&self) -> Vec<&String> {
+        self.profiles.keys().collect()
+    }
+
+    pub fn contains(&self, issuer: &str) -> bool {
+        self.profiles.contains_key(issuer)
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn profile(&self, issuer: &str) -> &IssuerProfile {
+        match self.profiles.get(issuer) {
+            Some(profile) => profile,
+            None => bail!("Unknown issuer {}", issuer),
+        }
+    }
+
+    // The (unverified) iss claim only selects which profile the token is
+    // checked against; Auth::validate_token verifies the signature and the
+    // issuer again before any claim is trusted.
+    #[throws(anyhow::Error)]
+    pub async fn auth_for_token(&self, token: &str) -> Auth {
+        let issuer = utils::unverified_issuer(token)?;
+        let profile = self.profile(&issuer)?;
+        let header = decode_header(token)?;
+        profile.auth(header.kid.as_deref()).await?
+    }
+}
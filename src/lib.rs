@@ -1,4 +1,21 @@
+pub mod arn;
+pub mod audit;
 pub mod auth;
+pub mod cache;
+pub mod certificates;
+pub mod context;
+pub mod failures;
+pub mod issuers;
+pub mod logging;
+pub mod metrics;
+pub mod rbac;
+pub mod redaction;
+pub mod routes;
 pub mod utils;
 pub mod structs;
-pub mod enums;
\ No newline at end of file
+pub mod telemetry;
+pub mod users;
+pub mod enums;
+
+#[cfg(test)]
+mod test_support;
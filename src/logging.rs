@@ -0,0 +1,164 @@
+use std::{cell::RefCell, future::Future, io::Write, time::{SystemTime, UNIX_EPOCH}};
+
+use env_logger::filter::{Builder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::enums::{Decision, FailureCategory};
+
+// What is known about the invocation being handled, attached to each of its records
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct LogFields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lambda_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+    // taken from the token before it is verified, so failures can be traced back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision: Option<Decision>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_category: Option<FailureCategory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+}
+
+impl LogFields {
+    pub fn new(lambda_request_id: String) -> Self {
+        Self { lambda_request_id: Some(lambda_request_id), ..Self::default() }
+    }
+}
+
+tokio::task_local! {
+    static FIELDS: RefCell<LogFields>;
+}
+
+// Runs an invocation with its own fields, so concurrent ones do not mix
+pub async fn scope<F: Future>(fields: LogFields, future: F) -> F::Output {
+    FIELDS.scope(RefCell::new(fields), future).await
+}
+
+// Outside of a scope there is nothing to update
+pub fn update<F: FnOnce(&mut LogFields)>(update: F) {
+    let _ = FIELDS.try_with(|fields| update(&mut fields.borrow_mut()));
+}
+
+pub fn current() -> Option<LogFields> {
+    FIELDS.try_with(|fields| fields.borrow().clone()).ok()
+}
+
+// One JSON object per line, filtered by RUST_LOG like env_logger
+pub struct JsonLogger {
+    filter: Filter,
+}
+
+impl JsonLogger {
+    pub fn from_env(env: &str) -> Self {
+        Self { filter: Builder::from_env(env).build() }
+    }
+
+    pub fn filter(&self) -> LevelFilter {
+        self.filter.filter()
+    }
+
+    pub fn format(record: &Record) -> Value {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut line = json!({
+            "timestamp": timestamp,
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        if let Some(Value::Object(fields)) = current().map(|fields| json!(fields)) {
+            line.as_object_mut().unwrap().extend(fields);
+        }
+        line
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            let _ = writeln!(std::io::stdout().lock(), "{}", Self::format(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use log::Level;
+
+    fn record(fields: Option<LogFields>) -> Value {
+        let format = || JsonLogger::format(&Record::builder()
+            .args(format_args!("Token is refused"))
+            .level(Level::Info)
+            .target("main.error")
+            .build());
+        match fields {
+            Some(fields) => {
+                let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+                runtime.block_on(scope(fields, async { format() }))
+            },
+            None => format(),
+        }
+    }
+
+    #[test]
+    fn test_format_without_fields() {
+        let line = record(None);
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "main.error");
+        assert_eq!(line["message"], "Token is refused");
+        assert!(line.get("lambda_request_id").is_none());
+    }
+
+    #[test]
+    fn test_format_with_fields() {
+        let mut fields = LogFields::new("8476a536-e9f4-11e8-9739-2dfe598c3fcd".to_string());
+        fields.kid = Some("auth0".to_string());
+        fields.decision = Some(Decision::Unauthorized);
+        fields.failure_category = Some(FailureCategory::Expired);
+        let line = record(Some(fields));
+        assert_eq!(line["lambda_request_id"], "8476a536-e9f4-11e8-9739-2dfe598c3fcd");
+        assert_eq!(line["kid"], "auth0");
+        assert_eq!(line["decision"], "unauthorized");
+        assert_eq!(line["failure_category"], "expired");
+        assert!(line.get("principal").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scopes_do_not_mix() {
+        let first = scope(LogFields::new("first".to_string()), async {
+            update(|fields| fields.principal = Some("auth0|1".to_string()));
+            tokio::task::yield_now().await;
+            current().unwrap()
+        });
+        let second = scope(LogFields::new("second".to_string()), async {
+            tokio::task::yield_now().await;
+            current().unwrap()
+        });
+        let (first, second) = tokio::join!(first, second);
+        assert_eq!(first.principal.as_deref(), Some("auth0|1"));
+        assert_eq!(second.principal, None);
+        assert_eq!(second.lambda_request_id.as_deref(), Some("second"));
+        // no scope, nothing to update
+        update(|fields| fields.principal = Some("nobody".to_string()));
+        assert!(current().is_none());
+    }
+}
@@ -1,116 +1,423 @@
-use std::env;
+use std::{env, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use log::debug;
+use log::{debug, error, info, warn, LevelFilter, Log};
+use tracing::{field, info_span, Instrument, Span};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
 use lambda_runtime::{handler_fn, Context, Error};
 use anyhow::Result;
-use serde_json::json;
-use structs::{APIGatewayCustomAuthorizerRequest, APIGatewayCustomAuthorizerResponse, APIGatewayPolicyBuilder};
-use auth::Auth;
+use serde_json::{json, Map, Value};
+use jsonwebtoken::{decode_header, TokenData};
+use jwt_authorizer::{
+    arn::MethodArn,
+    audit::{AuditRecord, AuditSink, FileAuditSink, StdoutAuditSink},
+    auth::Auth,
+    cache::{DEFAULT_JWKS_TTL, DEFAULT_MIN_REFRESH_INTERVAL},
+    context::ContextMapping,
+    enums::{
+        AudienceMatch, AuthorizerEvent, AuthorizerResponse, Decision, DirectoryFailureMode, FailureAction, FailureCategory, HttpApiResponseMode, HttpMethod,
+        LogFormat, StringOrArray, TokenSource,
+    },
+    failures::{self, FailurePolicy},
+    issuers::IssuerRegistry,
+    logging::{self, JsonLogger, LogFields},
+    metrics::{self, MetricsConfig},
+    rbac::RoleAuthorizer,
+    redaction::{self, ClaimRedactor, RedactingLogger},
+    routes::{self, token_scopes, RouteAuthorizer},
+    telemetry::{self, OtlpExporter},
+    users::{CachedUserDirectory, DynamoUserDirectory, UserCheck, DEFAULT_USERS_CACHE_TTL},
+    structs::{
+        APIGatewayCustomAuthorizerPolicy, APIGatewayCustomAuthorizerResponse, APIGatewayPolicyBuilder,
+        AuthError, DynamicClaims, HttpApiSimpleResponse, IssuerConfig, TimeValidation, default_principal_claim,
+    },
+    utils,
+};
 
-mod structs;
-mod enums;
-mod utils;
-mod auth;
+// Lives as long as the Lambda container, so warm invocations reuse it
+struct State {
+    issuers: IssuerRegistry,
+    // all routes are allowed when both are missing
+    routes: Option<RouteAuthorizer>,
+    roles: Option<RoleAuthorizer>,
+    failure_policy: FailurePolicy,
+    // where REQUEST authorizers look for the token
+    token_sources: Vec<TokenSource>,
+    http_api_response: HttpApiResponseMode,
+    // claims passed on to the integration
+    context: ContextMapping,
+    // claims left out of the logs
+    claim_redactor: ClaimRedactor,
+    metrics: Option<MetricsConfig>,
+    exporter: Option<OtlpExporter>,
+    // where each decision is recorded
+    audit: Option<Box<dyn AuditSink>>,
+    // blocked users, consulted once the token is valid
+    users: Option<UserCheck>,
+}
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> Result<(), Error> {
-    env_logger::init();
-    let func = handler_fn(execute);
+    // tokens are scrubbed from every record, whatever its target
+    let log_format = env::var("JWTAUTH_LOG_FORMAT").map_or(LogFormat::default(), |format| format.parse().expect("JWTAUTH_LOG_FORMAT must be json or text"));
+    let (logger, max_level): (Box<dyn Log>, LevelFilter) = match log_format {
+        LogFormat::Json => {
+            let logger = JsonLogger::from_env("RUST_LOG");
+            let max_level = logger.filter();
+            (Box::new(RedactingLogger::new(logger)), max_level)
+        },
+        LogFormat::Text => {
+            let logger = env_logger::Builder::from_default_env().build();
+            let max_level = logger.filter();
+            (Box::new(RedactingLogger::new(logger)), max_level)
+        },
+    };
+    log::set_max_level(max_level);
+    log::set_boxed_logger(logger)?;
+    // spans are only collected when there is a collector to send them to
+    let exporter = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().map(|endpoint| {
+        OtlpExporter::new(endpoint, env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string()))
+    }).transpose()?;
+    if let Some(exporter) = &exporter {
+        tracing::subscriber::set_global_default(Registry::default().with(exporter.layer()))?;
+    }
+    let keys_ttl = env_seconds("JWTAUTH_KEYS_CACHE_TTL").map_or(DEFAULT_JWKS_TTL, Duration::from_secs);
+    let min_refresh_interval = env_seconds("JWTAUTH_KEYS_MIN_REFRESH_INTERVAL").map_or(DEFAULT_MIN_REFRESH_INTERVAL, Duration::from_secs);
+    let issuer_configs: Vec<IssuerConfig> = match env::var("JWTAUTH_ISSUERS") {
+        Ok(issuers) => serde_json::from_str(&issuers).expect("JWTAUTH_ISSUERS must be a JSON list of issuers"),
+        // single issuer setup
+        Err(_) => vec![IssuerConfig {
+            issuer: env::var("JWTAUTH_TOKEN_ISSUER").expect("Please specify an issuer as env var"),
+            audience: StringOrArray::StrArray(
+                env::var("JWTAUTH_TOKEN_AUDIENCE").expect("Please specify an audience as env var")
+                .split(',').map(|audience| audience.trim().to_string()).collect()
+            ),
+            audience_match: match env::var("JWTAUTH_TOKEN_AUDIENCE_MATCH") {
+                Ok(audience_match) => audience_match.parse().expect("JWTAUTH_TOKEN_AUDIENCE_MATCH must be any or all"),
+                Err(_) => AudienceMatch::Any,
+            },
+            keys_repo: env::var("JWTAUTH_KEYS_REPO").ok(),
+            algorithms: env::var("JWTAUTH_TOKEN_ALGORITHMS").ok(),
+            x5c_trust_anchors: env::var("JWTAUTH_X5C_TRUST_ANCHORS").ok(),
+            required_claims: env::var("JWTAUTH_REQUIRED_CLAIMS").map_or(vec![], |claims| {
+                claims.split(',').map(|claim| claim.trim().to_string()).filter(|claim| !claim.is_empty()).collect()
+            }),
+            claims_namespace: env::var("JWTAUTH_CLAIMS_NAMESPACE").ok(),
+            principal_claim: env::var("JWTAUTH_PRINCIPAL_CLAIM").unwrap_or_else(|_| default_principal_claim()),
+            time_validation: TimeValidation {
+                leeway: env_seconds("JWTAUTH_TOKEN_LEEWAY").unwrap_or(0),
+                require_nbf: env::var("JWTAUTH_TOKEN_REQUIRE_NBF").as_deref() == Ok("true"),
+                max_age: env_seconds("JWTAUTH_TOKEN_MAX_AGE"),
+                max_lifetime: env_seconds("JWTAUTH_TOKEN_MAX_LIFETIME"),
+            },
+        }],
+    };
+    let state = Arc::new(State {
+        issuers: IssuerRegistry::from_configs(&issuer_configs, keys_ttl, min_refresh_interval).await?,
+        routes: match env::var("JWTAUTH_ROUTES") {
+            Ok(path) => Some(RouteAuthorizer::from_file(path)?),
+            Err(_) => None,
+        },
+        roles: match env::var("JWTAUTH_ROLES") {
+            Ok(path) => Some(RoleAuthorizer::from_file(path)?),
+            Err(_) => None,
+        },
+        failure_policy: match env::var("JWTAUTH_FAILURE_RESPONSE").as_deref() {
+            Ok("by_category") => FailurePolicy::by_category(),
+            Ok(action) => FailurePolicy::new(action.parse().expect("JWTAUTH_FAILURE_RESPONSE must be deny, unauthorized, error or by_category")),
+            Err(_) => FailurePolicy::default(),
+        }.with_actions(&env::var("JWTAUTH_FAILURE_RESPONSES").unwrap_or_default())?,
+        token_sources: env::var("JWTAUTH_TOKEN_SOURCES").unwrap_or_else(|_| "header:Authorization".to_string())
+            .split(',').map(str::parse).collect::<Result<_>>()?,
+        http_api_response: match env::var("JWTAUTH_HTTP_API_RESPONSE") {
+            Ok(mode) => mode.parse().expect("JWTAUTH_HTTP_API_RESPONSE must be simple or iam"),
+            Err(_) => HttpApiResponseMode::Iam,
+        },
+        context: match env::var("JWTAUTH_CONTEXT") {
+            Ok(path) => ContextMapping::from_file(path)?,
+            Err(_) => ContextMapping::default(),
+        },
+        claim_redactor: match env::var("JWTAUTH_REDACTED_CLAIMS") {
+            Ok(claims) => ClaimRedactor::new(claims.split(',').map(|claim| claim.trim().to_string()).filter(|claim| !claim.is_empty()).collect()),
+            Err(_) => ClaimRedactor::default(),
+        },
+        metrics: match env::var("JWTAUTH_METRICS_NAMESPACE") {
+            Ok(namespace) => Some(MetricsConfig::new(namespace).with_dimensions(
+                env::var("JWTAUTH_METRICS_DIMENSIONS").unwrap_or_else(|_| "Stage".to_string())
+                    .split(',').map(|dimension| dimension.trim().to_string()).filter(|dimension| !dimension.is_empty()).collect()
+            )?),
+            Err(_) => None,
+        },
+        exporter,
+        audit: match env::var("JWTAUTH_AUDIT_LOG").as_deref() {
+            Ok("stdout") => Some(Box::new(StdoutAuditSink)),
+            Ok(sink) => match sink.strip_prefix("file:") {
+                Some(path) => Some(Box::new(FileAuditSink::open(path, env::var("JWTAUTH_AUDIT_KEY").expect("JWTAUTH_AUDIT_KEY must be set with JWTAUTH_AUDIT_LOG=file:<path>").as_bytes())?)),
+                None => panic!("JWTAUTH_AUDIT_LOG must be stdout or file:<path>"),
+            },
+            Err(_) => None,
+        },
+        users: match env::var("JWTAUTH_USERS_TABLE") {
+            Ok(table) => {
+                let directory = DynamoUserDirectory::from_env(table).await
+                    .with_key_attribute(env::var("JWTAUTH_USERS_KEY").unwrap_or_else(|_| "principal_id".to_string()))
+                    .with_blocked_attribute(env::var("JWTAUTH_USERS_BLOCKED_ATTRIBUTE").unwrap_or_else(|_| "blocked".to_string()));
+                let ttl = env_seconds("JWTAUTH_USERS_CACHE_TTL").map_or(DEFAULT_USERS_CACHE_TTL, Duration::from_secs);
+                Some(UserCheck::new(
+                    Box::new(CachedUserDirectory::new(directory, ttl)),
+                    match env::var("JWTAUTH_USERS_FAILURE_MODE") {
+                        Ok(mode) => mode.parse().expect("JWTAUTH_USERS_FAILURE_MODE must be open or closed"),
+                        Err(_) => DirectoryFailureMode::Closed,
+                    },
+                ))
+            },
+            Err(_) => None,
+        },
+    });
+    // both grant routes into the same policy
+    if let (Some(route_authorizer), Some(role_authorizer)) = (&state.routes, &state.roles) {
+        routes::check_exact(&[route_authorizer.configured_routes(), role_authorizer.configured_routes()].concat())?;
+    }
+    let func = handler_fn(move |event, context: Context| {
+        let state = state.clone();
+        // continues the X-Ray trace of the invocation
+        let span = info_span!(
+            "authorizer",
+            xray_trace_header = %context.xray_trace_id,
+            lambda_request_id = %context.request_id,
+            principal = field::Empty,
+            decision = field::Empty,
+        );
+        telemetry::continue_xray_trace(&span, &context.xray_trace_id);
+        logging::scope(LogFields::new(context.request_id.clone()), async move {
+            let (response, recorder) = metrics::scope(execute(event, context, state.clone()).instrument(span)).await;
+            if let Some(metrics) = &state.metrics {
+                metrics.emit(&recorder);
+            }
+            if let Some(exporter) = &state.exporter {
+                if let Err(error) = exporter.flush().await {
+                    warn!(target: "main.traces", "Could not export the traces: {}", error);
+                }
+            }
+            response
+        })
+    });
     lambda_runtime::run(func).await?;
     Ok(())
 }
 
-async fn execute(event: APIGatewayCustomAuthorizerRequest, _context: Context) -> Result<APIGatewayCustomAuthorizerResponse, Error> {
-    debug!(target: "main.arn", "Method ARN: {}", event.method_arn);
-    let token = str::replace(&event.authorization_token.to_string(), "Bearer ", "");
-    debug!(target: "main.token", "Token: {:?}", &token);
+fn env_seconds(name: &str) -> Option<u64> {
+    env::var(name).ok().map(|seconds| seconds.parse().unwrap_or_else(|_| panic!("{} must be a number of seconds", name)))
+}
+
+// Validates the token of the request against the issuer it claims to come from
+async fn authenticate(event: &AuthorizerEvent, state: &State) -> Result<(TokenData<DynamicClaims>, Auth)> {
+    let token = event.token(&state.token_sources)?;
+    debug!(target: "main.token", "Token: {}", redaction::token_summary(&token));
+    let issuer = utils::unverified_issuer(&token).ok();
+    // every forged iss would make a new metric series
+    metrics::dimension("Issuer", match &issuer {
+        Some(issuer) if state.issuers.contains(issuer) => issuer,
+        _ => "unknown",
+    });
+    logging::update(|fields| {
+        fields.issuer = issuer;
+        fields.kid = decode_header(&token).ok().and_then(|header| header.kid);
+    });
+    // unknown issuers and unreachable key sets are refused like any other invalid token
+    let mut auth = state.issuers.auth_for_token(&token).await?;
+    let started = Instant::now();
+    let token_data = auth.validate_token(&token);
+    metrics::timing(metrics::VALIDATION_TIME, started.elapsed());
+    let token_data = token_data?;
+    Ok((token_data, auth))
+}
+
+// The routes a valid token may call, along with the rule that grants the call being called
+// when the event tells, or why it is not granted. The policy denies an ungranted call by
+// itself, and API Gateway caches it for the other calls of the token.
+fn authorize(token_claims: &DynamicClaims, route: Option<(HttpMethod, &str)>, state: &State, builder: APIGatewayPolicyBuilder) -> Result<(APIGatewayCustomAuthorizerPolicy, Result<Option<String>, AuthError>)> {
+    let (builder, matched_rule) = match (&state.routes, &state.roles) {
+        // allows access to all resources in the API
+        (None, None) => (builder.allow_all_methods(), Ok(Some("*".to_string()))),
+        // only the routes granted by the token scopes and roles
+        (route_authorizer, role_authorizer) => {
+            let (mut allowed, mut configured) = (vec![], vec![]);
+            if let Some(route_authorizer) = route_authorizer {
+                allowed.extend(route_authorizer.allowed_routes(&token_scopes(token_claims)));
+                configured.extend(route_authorizer.configured_routes());
+            }
+            if let Some(role_authorizer) = role_authorizer {
+                allowed.extend(role_authorizer.allowed_routes(&role_authorizer.token_roles(token_claims)));
+                configured.extend(role_authorizer.configured_routes());
+            }
+            if allowed.is_empty() {
+                Err(AuthError::new(FailureCategory::InsufficientScope, "Token grants none of the configured routes"))?
+            }
+            let matched_rule = match route {
+                Some((method, path)) => match routes::matching_route(&allowed, method, path) {
+                    Some((method, pattern)) => Ok(Some(format!("{} {}", method, pattern))),
+                    None => Err(AuthError::new(FailureCategory::InsufficientScope, format!("Token does not grant {} {}", method, path))),
+                },
+                // TOKEN authorizers do not know the route being called
+                None => Ok(None),
+            };
+            (routes::apply_routes(builder, &allowed, &configured), matched_rule)
+        },
+    };
+    Ok((builder.build()?, matched_rule))
+}
+
+// The context may carry claims, so only its keys are logged, as in context.apply
+fn log_response(response: &AuthorizerResponse) {
+    let keys = |context: &Value| context.as_object().map(|context| context.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+    match response {
+        AuthorizerResponse::Policy(response) => debug!(target: "main.response", "Gateway Response: principal {}, policy {:?}, context keys {:?}", response.principal_id, response.policy_document, keys(&response.context)),
+        AuthorizerResponse::Simple(response) => debug!(target: "main.response", "Gateway Response: authorized {}, context keys {:?}", response.is_authorized, keys(&response.context)),
+    }
+}
+
+// Key-value pairs API Gateway makes available as $context.authorizer.<key>, cached with the policy
+fn response_context(token_claims: &DynamicClaims, auth: &Auth, state: &State) -> Result<Value> {
+    let mut context = Map::new();
+    context.insert("audience".to_string(), json!(auth.matched_audiences.join(",")));
+    Ok(Value::Object(state.context.apply(token_claims, auth.claims_namespace.as_deref(), context)?))
+}
+
+// Sent to the audit sink, if any: a failure to record is logged, the decision stands
+fn audit(state: &State, record: AuditRecord) {
+    if let Some(sink) = &state.audit {
+        if let Err(error) = sink.record(&record) {
+            error!(target: "main.audit", "Could not record the decision: {}", error);
+        }
+    }
+}
+
+async fn execute(event: Value, _context: Context, state: Arc<State>) -> Result<AuthorizerResponse, Error> {
+    // TOKEN or REQUEST authorizer of a REST API, or HTTP API authorizer
+    let event = AuthorizerEvent::from_value(event)?;
+    logging::update(|fields| fields.api_request_id = event.request_id().map(str::to_string));
+    let simple_response = matches!(event, AuthorizerEvent::HttpApi(_)) && state.http_api_response == HttpApiResponseMode::Simple;
+    debug!(target: "main.arn", "Method ARN: {}", event.method_arn());
     // this could be accomplished in a number of ways:
     // 1. Validate and Decode JWT and produce the principal user identifier associated with the token
-    // 2. Lookup in DynamoBD (user blocked?), TODO: check token.sub in our DB
+    // 2. Lookup in DynamoDB (user blocked?), see JWTAUTH_USERS_TABLE
   
     // if the token is valid, a policy must be generated which will allow or deny access to the client
     //     - if access is denied, the client will recieve a 403 Access Denied response
     //     - if access is allowed, API Gateway will proceed with the backend integration configured on the method that was called
+    //     - if the client has to authenticate (again), the Unauthorized error makes it a 401
 
     // the policy is cached for 5 minutes by default (TTL is configurable in the authorizer)
     // and will apply to subsequent calls to any method/resource in the API
     // made with the same token
-    let tmp: Vec<&str> = event.method_arn.split(":").collect();
-    let api_gateway_arn_tmp: Vec<&str> = tmp[5].split("/").collect();
-    let aws_account_id = tmp[4];
-    let region = tmp[3];
-    let rest_api_id = api_gateway_arn_tmp[0];
-    let stage = api_gateway_arn_tmp[1];
-
-    debug!(target: "main.arn","aws_account_id: {}, region: {}, rest_api_id: {}, stage: {}", aws_account_id, region, rest_api_id, stage);
+    // a malformed ARN leaves nothing to build a policy for
+    let arn: MethodArn = event.method_arn().parse()?;
+    debug!(target: "main.arn", "{:?}", arn);
+    metrics::dimension("Stage", &arn.stage);
+    metrics::dimension("ApiId", &arn.api_id);
+    metrics::dimension("Region", &arn.region);
+    let builder = || APIGatewayPolicyBuilder::from_method_arn(&arn);
 
-    // TODO! -- add additional key-value pairs associated with the authenticated principal
-    // these are made available by APIGW like so: $context.authorizer.<key>
-    // additional context is cached
-    let audience = env::var("JWTAUTH_TOKEN_AUDIENCE").expect("Please specify an audience as env var");
-    let issuer = env::var("JWTAUTH_TOKEN_ISSUER").expect("Please specify an issuer as env var");
-    // TODO: cache this
-    let keys_repo = env::var("JWTAUTH_KEYS_REPO").expect("Please specify a keys repo (jwk) as env var");
-    let keys = utils::get_jwks(keys_repo).await?;
-    let mut auth = Auth::new(
-        audience, issuer, keys
-    );
-    match auth.validate_token(&token.to_string()) {
-        Ok(token_data) => {
-            debug!(target: "main.ok", "Token is valid, claims: {:?}", &token_data);
+    // the claims of the token are passed on in the context, see JWTAUTH_CONTEXT
+    let outcome = async {
+        let (token_data, auth) = authenticate(&event, &state).await?;
+        if let (Some(users), Some(principal_id)) = (&state.users, &auth.principal_id) {
+            users.check(principal_id).await?;
+        }
+        let (policy, matched_rule) = authorize(&token_data.claims, event.route(), &state, builder())?;
+        let context = response_context(&token_data.claims, &auth, &state)?;
+        Ok::<_, anyhow::Error>((token_data, auth, policy, matched_rule, context))
+    }.await;
+    // what the audit record knows about the token, whether or not it is valid
+    let token = event.token(&state.token_sources).ok();
+    let audit_record = |decision, principal, jti, matched_rule| AuditRecord {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        principal,
+        jti,
+        token_fingerprint: token.as_deref().map(redaction::fingerprint),
+        issuer: token.as_deref().and_then(|token| utils::unverified_issuer(token).ok()),
+        method_arn: event.method_arn().to_string(),
+        decision,
+        matched_rule,
+        failure_category: None,
+        failure_reason: None,
+    };
+    match outcome {
+        Ok((token_data, auth, policy, matched_rule, context)) => {
+            debug!(target: "main.ok", "Token is valid, header: {:?}, claims: {:?}", &token_data.header, state.claim_redactor.redact(&token_data.claims));
             let token_claims = token_data.claims;
-            let principal_id = token_claims.sub;
-            // allows access to all resources in the API
-            let policy = APIGatewayPolicyBuilder::new(region, aws_account_id, rest_api_id, stage)
-            .allow_all_methods()
-            .build();
-            // TODO: reduce access to just the required resource (botos, users...) instead of *
+            let principal_id = auth.principal_id.unwrap_or_default();
             debug!(target: "main.policy", "Policy is: {:?}", &policy);
-            let user_id = token_claims.user_id;
-            let gateway_response = APIGatewayCustomAuthorizerResponse {
-                principal_id: user_id.clone(),
-                policy_document: policy,
-                context: json!({
-                    "sub": principal_id,
-                    "user_id": user_id
+            // the call may not be granted, the rest of the policy still applies
+            let decision = if matched_rule.is_ok() { Decision::Allow } else { Decision::Deny };
+            let refusal = matched_rule.as_ref().err();
+            logging::update(|fields| {
+                fields.principal = Some(principal_id.clone());
+                fields.decision = Some(decision);
+                fields.failure_category = refusal.map(|refusal| refusal.category);
+                fields.failure_reason = refusal.map(|refusal| refusal.msg.clone());
+            });
+            Span::current().record("principal", principal_id.as_str()).record("decision", field::display(decision));
+            match refusal {
+                None => info!(target: "main.decision", "Request allowed"),
+                Some(refusal) => {
+                    info!(target: "main.decision", "Request refused");
+                    metrics::count(&format!("{}.{}", metrics::FAILURE, refusal.category));
+                },
+            }
+            metrics::count(&format!("{}.{}", metrics::DECISION, decision));
+            audit(&state, AuditRecord {
+                failure_category: refusal.map(|refusal| refusal.category),
+                failure_reason: refusal.map(|refusal| refusal.msg.clone()),
+                ..audit_record(decision, Some(principal_id.clone()), token_claims.claim_string("jti", None), matched_rule.clone().unwrap_or_default())
+            });
+            let gateway_response = if simple_response {
+                AuthorizerResponse::Simple(HttpApiSimpleResponse { is_authorized: decision == Decision::Allow, context })
+            } else {
+                AuthorizerResponse::Policy(APIGatewayCustomAuthorizerResponse {
+                    principal_id,
+                    policy_document: policy,
+                    context
                 })
             };
-            debug!(target: "main.response", "Gateway Response: {:?}", &gateway_response);
-            return Ok(gateway_response)
-            // if we want to deny access, for example, the user is blocked
-            // Access Denied, 401
-            // let policy = APIGatewayPolicyBuilder::new(region, aws_account_id, rest_api_id, stage)
-            // .deny_all_methods()
-            // .build();
-            // return APIGatewayCustomAuthorizerResponse {
-            //     principal_id: principal_id.to_string(),
-            //     policy_document: policy,
-            //     context: json!({
-            //     "stringKey": "stringval",
-            //     "numberKey": 123,
-            //     "booleanKey": true
-            //   })
-            // }
+            log_response(&gateway_response);
+            Ok(gateway_response)
         },
         Err(error) => {
-            // Forbidden, 403
-            let error_string = format!("Error validating token: {}", error.root_cause());
-            debug!(target: "main.error", "Token is invalid, {}", error_string);
-            // allows access to all resources in the API
-            let policy = APIGatewayPolicyBuilder::new(region, aws_account_id, rest_api_id, stage)
-            .deny_method(enums::HttpMethod::GET, "boto".to_string())
-            .build();
-            debug!(target: "main.policy", "Policy is: {:?}", &policy);
-            let gateway_response = APIGatewayCustomAuthorizerResponse {
-                principal_id: "user".to_string(),
-                policy_document: policy,
-                context: json!({
-                    "messageDescription": error_string,
-                    "messageType": "Access Denied".to_string()
-                })
+            debug!(target: "main.error", "Token is refused, {}", error);
+            let category = failures::classify(&error);
+            let decision = match (&event, state.failure_policy.action(category)) {
+                (_, FailureAction::Error) => Decision::Error,
+                (AuthorizerEvent::Token(_) | AuthorizerEvent::Request(_), FailureAction::Unauthorized) => Decision::Unauthorized,
+                _ => Decision::Deny,
             };
-            debug!(target: "main.response", "Gateway Response: {:?}", &gateway_response);
-            return Ok(gateway_response)
+            Span::current().record("decision", field::display(decision));
+            logging::update(|fields| {
+                fields.decision = Some(decision);
+                fields.failure_category = Some(category);
+                fields.failure_reason = Some(error.root_cause().to_string());
+            });
+            info!(target: "main.decision", "Request refused");
+            metrics::count(&format!("{}.{}", metrics::DECISION, decision));
+            metrics::count(&format!("{}.{}", metrics::FAILURE, category));
+            audit(&state, AuditRecord {
+                failure_category: Some(category),
+                failure_reason: Some(error.root_cause().to_string()),
+                ..audit_record(decision, None, None, None)
+            });
+            // API Gateway answers 500 whatever the kind of API, and the client may retry
+            if decision == Decision::Error {
+                return Err(error.into());
+            }
+            let gateway_response = match event {
+                // HTTP APIs cannot be told to answer 401
+                AuthorizerEvent::HttpApi(_) if simple_response => AuthorizerResponse::Simple(failures::simple_response(&error)),
+                AuthorizerEvent::HttpApi(_) => AuthorizerResponse::Policy(failures::deny_response(&error, builder())?),
+                // Unauthorized (401) or Forbidden (403), depending on the failure policy
+                _ => AuthorizerResponse::Policy(state.failure_policy.respond(&error, builder())?),
+            };
+            log_response(&gateway_response);
+            Ok(gateway_response)
         }
-    };
+    }
 }
@@ -0,0 +1,192 @@
+use std::{cell::RefCell, collections::BTreeMap, future::Future, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use anyhow::bail;
+use fehler::throws;
+use serde_json::{json, Map, Value};
+
+use crate::enums::MetricUnit;
+
+pub const VALIDATION_TIME: &str = "ValidationTime";
+pub const JWKS_FETCH: &str = "JwksFetch";
+pub const JWKS_FETCH_TIME: &str = "JwksFetchTime";
+pub const JWKS_FETCH_ERROR: &str = "JwksFetchError";
+pub const JWKS_CACHE_HIT: &str = "JwksCacheHit";
+pub const JWKS_CACHE_MISS: &str = "JwksCacheMiss";
+pub const USER_CACHE_HIT: &str = "UserCacheHit";
+pub const USER_CACHE_MISS: &str = "UserCacheMiss";
+pub const USER_LOOKUP_TIME: &str = "UserLookupTime";
+pub const USER_LOOKUP_ERROR: &str = "UserLookupError";
+// followed by the decision (Decision.allow) or the failure category (Failure.expired)
+pub const DECISION: &str = "Decision";
+pub const FAILURE: &str = "Failure";
+
+// What an invocation can be broken down by
+pub const DIMENSIONS: [&str; 4] = ["Stage", "ApiId", "Region", "Issuer"];
+
+// Metrics and dimension values recorded during one invocation
+#[derive(Debug, Default)]
+pub struct Recorder {
+    metrics: BTreeMap<String, (MetricUnit, Vec<f64>)>,
+    dimensions: BTreeMap<String, String>,
+}
+
+impl Recorder {
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    // counts are summed, timings kept one by one
+    pub fn value(&self, name: &str) -> Option<Value> {
+        self.metrics.get(name).map(|(unit, values)| match (unit, &values[..]) {
+            (MetricUnit::Count, values) => json!(values.iter().sum::<f64>()),
+            (_, [value]) => json!(value),
+            (_, values) => json!(values),
+        })
+    }
+}
+
+tokio::task_local! {
+    static RECORDER: RefCell<Recorder>;
+}
+
+// Runs an invocation and hands back what it recorded
+pub async fn scope<F: Future>(future: F) -> (F::Output, Recorder) {
+    RECORDER.scope(RefCell::new(Recorder::default()), async {
+        let output = future.await;
+        (output, RECORDER.with(RefCell::take))
+    }).await
+}
+
+// Outside of a scope nothing is recorded
+fn record(name: &str, unit: MetricUnit, value: f64) {
+    let _ = RECORDER.try_with(|recorder| {
+        recorder.borrow_mut().metrics.entry(name.to_string()).or_insert_with(|| (unit, vec![])).1.push(value);
+    });
+}
+
+pub fn count(name: &str) {
+    record(name, MetricUnit::Count, 1.0)
+}
+
+pub fn timing(name: &str, elapsed: Duration) {
+    record(name, MetricUnit::Milliseconds, elapsed.as_secs_f64() * 1000.0)
+}
+
+pub fn dimension(name: &str, value: &str) {
+    let _ = RECORDER.try_with(|recorder| {
+        recorder.borrow_mut().dimensions.insert(name.to_string(), value.to_string());
+    });
+}
+
+// CloudWatch Embedded Metric Format: printed to stdout, extracted by CloudWatch Logs
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub namespace: String,
+    pub dimensions: Vec<String>,
+}
+
+impl MetricsConfig {
+    pub fn new(namespace: String) -> Self {
+        Self { namespace, dimensions: vec![] }
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn with_dimensions(mut self, dimensions: Vec<String>) -> Self {
+        if let Some(unknown) = dimensions.iter().find(|dimension| !DIMENSIONS.contains(&dimension.as_str())) {
+            bail!("Unknown metrics dimension {}, expected one of {}", unknown, DIMENSIONS.join(", "))
+        }
+        self.dimensions = dimensions;
+        self
+    }
+
+    // None when nothing was recorded
+    pub fn document(&self, recorder: &Recorder, timestamp: SystemTime) -> Option<Value> {
+        if recorder.is_empty() {
+            return None;
+        }
+        let mut document = Map::new();
+        // a dimension without a value would drop the metrics
+        for dimension in &self.dimensions {
+            document.insert(dimension.clone(), json!(recorder.dimensions.get(dimension).map_or("unknown", String::as_str)));
+        }
+        let mut metrics = vec![];
+        for (name, (unit, _)) in &recorder.metrics {
+            metrics.push(json!({ "Name": name, "Unit": unit }));
+            document.insert(name.clone(), recorder.value(name)?);
+        }
+        document.insert("_aws".to_string(), json!({
+            "Timestamp": timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "CloudWatchMetrics": [{
+                "Namespace": self.namespace,
+                "Dimensions": [self.dimensions],
+                "Metrics": metrics,
+            }],
+        }));
+        Some(Value::Object(document))
+    }
+
+    pub fn emit(&self, recorder: &Recorder) {
+        if let Some(document) = self.document(recorder, SystemTime::now()) {
+            println!("{}", document);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn config() -> MetricsConfig {
+        MetricsConfig::new("JwtAuthorizer".to_string()).with_dimensions(vec!["Stage".to_string(), "Issuer".to_string()]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_document() {
+        let ((), recorder) = scope(async {
+            dimension("Stage", "prod");
+            dimension("ApiId", "5q06q4o1qe");
+            count(JWKS_CACHE_HIT);
+            count(JWKS_CACHE_HIT);
+            timing(VALIDATION_TIME, Duration::from_micros(1500));
+            count(&format!("{}.{}", DECISION, "allow"));
+        }).await;
+        let document = config().document(&recorder, UNIX_EPOCH + Duration::from_secs(1600000000)).unwrap();
+        assert_eq!(document, json!({
+            "_aws": {
+                "Timestamp": 1600000000000u64,
+                "CloudWatchMetrics": [{
+                    "Namespace": "JwtAuthorizer",
+                    "Dimensions": [["Stage", "Issuer"]],
+                    "Metrics": [
+                        { "Name": "Decision.allow", "Unit": "Count" },
+                        { "Name": "JwksCacheHit", "Unit": "Count" },
+                        { "Name": "ValidationTime", "Unit": "Milliseconds" },
+                    ],
+                }],
+            },
+            "Stage": "prod",
+            "Issuer": "unknown",
+            "Decision.allow": 1.0,
+            "JwksCacheHit": 2.0,
+            "ValidationTime": 1.5,
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_nothing_recorded() {
+        let ((), recorder) = scope(async { dimension("Stage", "prod") }).await;
+        assert!(config().document(&recorder, SystemTime::now()).is_none());
+        // no scope, nothing to record into
+        count(JWKS_FETCH);
+        timing(VALIDATION_TIME, Duration::from_millis(3));
+        let ((), recorder) = scope(async { timing(JWKS_FETCH_TIME, Duration::from_millis(2)); timing(JWKS_FETCH_TIME, Duration::from_millis(4)) }).await;
+        assert_eq!(recorder.value(JWKS_FETCH_TIME), Some(json!([2.0, 4.0])));
+        assert_eq!(recorder.value(VALIDATION_TIME), None);
+    }
+
+    #[test]
+    fn test_unknown_dimension() {
+        assert!(MetricsConfig::new("JwtAuthorizer".to_string()).with_dimensions(vec!["Principal".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::path::Path;
+
+use anyhow::bail;
+use fehler::throws;
+use log::debug;
+use serde_json::Value;
+
+use crate::{enums::HttpMethod, routes, structs::{APIGatewayPolicyBuilder, DynamicClaims, RolesConfig}};
+
+// Maps the roles (or groups) of a token to the routes they may call
+#[derive(Debug)]
+pub struct RoleAuthorizer {
+    config: RolesConfig,
+}
+
+impl RoleAuthorizer {
+    #[throws(anyhow::Error)]
+    pub fn new(config: RolesConfig) -> Self {
+        for (name, role) in &config.roles {
+            for parent in &role.inherits {
+                if !config.roles.contains_key(parent) {
+                    bail!("Role {} inherits from unknown role {}", name, parent)
+                }
+            }
+            for route in &role.routes {
+                if !route.path.starts_with('/') {
+                    bail!("Route path {} of role {} must start with /", route.path, name)
+                }
+            }
+            check_cycles(&config, name, &mut vec![])?;
+        }
+        let routes: Vec<(HttpMethod, String)> = config.roles.values().flat_map(|role| role.routes.iter().map(|route| (route.method, route.path.clone()))).collect();
+        routes::check_exact(&routes)?;
+        Self { config }
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_json(json: &str) -> Self {
+        Self::new(serde_json::from_str(json)?)?
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
+        Self::from_json(&std::fs::read_to_string(path)?)?
+    }
+
+    // The roles claim may be a list or a space/comma separated string. Its name is
+    // looked up as is first, so namespaced claims work, then as a dotted path.
+    pub fn token_roles(&self, claims: &DynamicClaims) -> Vec<String> {
+        let value = match claims.get(&self.config.roles_claim) {
+            Some(value) => Some(value),
+            None => {
+                let mut path = self.config.roles_claim.split('.');
+                let first = claims.get(path.next().unwrap_or_default());
+                path.fold(first, |value, key| value.and_then(|value| value.get(key)))
+            }
+        };
+        match value {
+            Some(Value::String(roles)) => roles.split(|c: char| c == ',' || c.is_whitespace()).filter(|role| !role.is_empty()).map(str::to_string).collect(),
+            Some(Value::Array(roles)) => roles.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            _ => vec![],
+        }
+    }
+
+    // The given roles and every role they inherit from, unknown roles are ignored
+    pub fn effective_roles(&self, roles: &[String]) -> Vec<String> {
+        let mut effective: Vec<String> = vec![];
+        let mut pending: Vec<&String> = roles.iter().rev().collect();
+        while let Some(role) = pending.pop() {
+            if effective.contains(role) {
+                continue;
+            }
+            match self.config.roles.get(role) {
+                Some(config) => {
+                    effective.push(role.clone());
+                    pending.extend(config.inherits.iter().rev());
+                },
+                None => debug!(target: "rbac.effective_roles", "Ignoring unknown role {}", role),
+            }
+        }
+        effective
+    }
+
+    pub fn allowed_routes(&self, roles: &[String]) -> Vec<(HttpMethod, String)> {
+        let mut routes = vec![];
+        for role in self.effective_roles(roles) {
+            for route in &self.config.roles[&role].routes {
+                if !routes.contains(&(route.method, route.path.clone())) {
+                    routes.push((route.method, route.path.clone()));
+                }
+            }
+        }
+        routes
+    }
+
+    // Sorted, so the generated policy does not depend on the map order
+    pub fn configured_routes(&self) -> Vec<(HttpMethod, String)> {
+        let mut names: Vec<&String> = self.config.roles.keys().collect();
+        names.sort();
+        names.iter().flat_map(|name| self.config.roles[*name].routes.iter().map(|route| (route.method, route.path.clone()))).collect()
+    }
+
+    pub fn apply(&self, builder: APIGatewayPolicyBuilder, roles: &[String]) -> APIGatewayPolicyBuilder {
+        debug!(target: "rbac.apply", "Roles: {:?}", roles);
+        routes::apply_routes(builder, &self.allowed_routes(roles), &self.configured_routes())
+    }
+}
+
+#[throws(anyhow::Error)]
+fn check_cycles(config: &RolesConfig, role: &str, path: &mut Vec<String>) {
+    if path.iter().any(|visited| visited == role) {
+        bail!("Role inheritance cycle: {} -> {}", path.join(" -> "), role)
+    }
+    path.push(role.to_string());
+    for parent in &config.roles[role].inherits {
+        check_cycles(config, parent, path)?;
+    }
+    path.pop();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::policy_allows;
+    use serde_json::json;
+
+    fn authorizer() -> RoleAuthorizer {
+        RoleAuthorizer::new(serde_json::from_value(json!({
+            "roles_claim": "https://boto.io/claims/roles",
+            "roles": {
+                "viewer": { "routes": [{ "method": "GET", "path": "/botos/*" }] },
+                "editor": { "inherits": ["viewer"], "routes": [{ "method": "POST", "path": "/botos" }] },
+                "admin": { "inherits": ["editor"], "routes": [{ "method": "*", "path": "/users/*" }] },
+                "auditor": { "inherits": ["viewer"] },
+            },
+        })).unwrap()).unwrap()
+    }
+
+    fn roles(roles: &[&str]) -> Vec<String> {
+        roles.iter().map(|role| role.to_string()).collect()
+    }
+
+    #[test]
+    fn test_token_roles() {
+        let authorizer = authorizer();
+        let claims: DynamicClaims = serde_json::from_value(json!({ "https://boto.io/claims/roles": ["editor", "auditor"] })).unwrap();
+        assert_eq!(authorizer.token_roles(&claims), roles(&["editor", "auditor"]));
+        let authorizer = RoleAuthorizer::from_json(r#"{ "roles_claim": "realm_access.roles", "roles": {} }"#).unwrap();
+        let claims: DynamicClaims = serde_json::from_value(json!({ "realm_access": { "roles": "editor, viewer" } })).unwrap();
+        assert_eq!(authorizer.token_roles(&claims), roles(&["editor", "viewer"]));
+        assert!(authorizer.token_roles(&DynamicClaims::default()).is_empty());
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let authorizer = authorizer();
+        assert_eq!(authorizer.effective_roles(&roles(&["admin"])), roles(&["admin", "editor", "viewer"]));
+        assert_eq!(authorizer.effective_roles(&roles(&["auditor", "unknown", "viewer"])), roles(&["auditor", "viewer"]));
+        assert_eq!(authorizer.allowed_routes(&roles(&["editor"])), vec![
+            (HttpMethod::POST, "/botos".to_string()),
+            (HttpMethod::GET, "/botos/*".to_string()),
+        ]);
+        assert_eq!(authorizer.allowed_routes(&roles(&["auditor"])), vec![(HttpMethod::GET, "/botos/*".to_string())]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let builder = APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod");
+        let policy = authorizer().apply(builder, &roles(&["editor"])).build().unwrap();
+        let statements = serde_json::to_value(&policy.Statement).unwrap();
+        assert_eq!(policy.Statement.len(), 2);
+        assert_eq!(statements[0]["Resource"], json!([
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/GET/botos/*",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/POST/botos",
+        ]));
+        // * /users/* of admin, per method: */users/* would also refuse GET /botos/42/users/1
+        let users: Vec<String> = ["DELETE", "GET", "HEAD", "OPTIONS", "PATCH", "POST", "PUT"].iter()
+            .map(|method| format!("arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/{}/users/*", method))
+            .collect();
+        assert_eq!(statements[1]["Effect"], "Deny");
+        assert_eq!(statements[1]["Resource"], json!(users));
+        let policy = authorizer().apply(APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod"), &roles(&["viewer"])).build().unwrap();
+        let statements = serde_json::to_value(&policy.Statement).unwrap();
+        assert_eq!(statements[1]["Effect"], "Deny");
+        assert!(statements[1]["Resource"].as_array().unwrap().contains(&json!("arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/POST/botos")));
+    }
+
+    #[test]
+    fn test_parameters_do_not_over_grant_in_the_policy() {
+        let authorizer = RoleAuthorizer::from_json(&json!({
+            "roles_claim": "roles",
+            "roles": {
+                "reader": { "routes": [{ "method": "GET", "path": "/users/{id}" }] },
+                "writer": { "routes": [{ "method": "POST", "path": "/users/{id}/botos" }] },
+            },
+        }).to_string()).unwrap();
+        let builder = APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod");
+        let policy = authorizer.apply(builder, &roles(&["reader"])).build().unwrap();
+        assert!(policy_allows(&policy, HttpMethod::GET, "/users/1"));
+        assert!(!policy_allows(&policy, HttpMethod::GET, "/users/1/x"));
+        assert!(!policy_allows(&policy, HttpMethod::POST, "/users/1/botos"));
+    }
+
+    #[test]
+    fn test_invalid_roles() {
+        let unknown = r#"{ "roles_claim": "roles", "roles": { "editor": { "inherits": ["viewer"] } } }"#;
+        assert!(RoleAuthorizer::from_json(unknown).unwrap_err().to_string().contains("unknown role viewer"));
+        let cycle = r#"{ "roles_claim": "roles", "roles": { "a": { "inherits": ["b"] }, "b": { "inherits": ["a"] } } }"#;
+        assert!(RoleAuthorizer::from_json(cycle).unwrap_err().to_string().contains("cycle"));
+        let inexact = r#"{ "roles_claim": "roles", "roles": { "a": { "routes": [{ "method": "GET", "path": "/users/{id}" }] }, "b": { "routes": [{ "method": "GET", "path": "/users/{id}/botos" }] } } }"#;
+        assert!(RoleAuthorizer::from_json(inexact).unwrap_err().to_string().contains("cannot both be granted exactly"));
+    }
+}
@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+
+use jsonwebtoken::decode_header;
+use log::{Log, Metadata, Record};
+use openssl::sha::sha256;
+use serde_json::Value;
+
+use crate::structs::DynamicClaims;
+
+pub const REDACTED: &str = "<redacted>";
+
+// OpenID Connect standard claims about the person behind the token
+pub const DEFAULT_REDACTED_CLAIMS: [&str; 11] = [
+    "name", "given_name", "family_name", "middle_name", "nickname", "preferred_username",
+    "email", "phone_number", "address", "birthdate", "picture",
+];
+
+// Identifies a token in the logs without revealing it
+pub fn fingerprint(token: &str) -> String {
+    let digest = sha256(token.as_bytes());
+    format!("sha256:{}", digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+}
+
+// The fingerprint plus what the header tells, which is not secret
+pub fn token_summary(token: &str) -> String {
+    match decode_header(token) {
+        Ok(header) => format!("{} (alg: {:?}, kid: {:?}, typ: {:?})", fingerprint(token), header.alg, header.kid, header.typ),
+        Err(_) => format!("{} (not a JWT)", fingerprint(token)),
+    }
+}
+
+// Replaces anything shaped like a JWS or JWE compact serialization by its fingerprint
+pub fn redact_tokens(text: &str) -> Cow<'_, str> {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    let mut redacted = String::new();
+    let mut copied = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find(is_token_char) {
+        let length = rest[start..].find(|c: char| !is_token_char(c)).unwrap_or(rest.len() - start);
+        // dots around the token belong to the sentence
+        let run = &rest[start..start + length];
+        let candidate = run.trim_matches('.');
+        let offset = text.len() - rest.len() + start + (run.len() - run.trim_start_matches('.').len());
+        if is_jwt(candidate) {
+            redacted.push_str(&text[copied..offset]);
+            redacted.push_str(&format!("<token {}>", fingerprint(candidate)));
+            copied = offset + candidate.len();
+        }
+        rest = &rest[start + length..];
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    redacted.push_str(&text[copied..]);
+    Cow::Owned(redacted)
+}
+
+// base64url JSON headers always start with eyJ ({")
+fn is_jwt(candidate: &str) -> bool {
+    let segments: Vec<&str> = candidate.split('.').collect();
+    (segments.len() == 3 || segments.len() == 5) && segments[0].starts_with("eyJ")
+}
+
+// Claims that must not end up in the logs, by name or namespaced name
+#[derive(Debug, Clone)]
+pub struct ClaimRedactor {
+    claims: Vec<String>,
+}
+
+impl Default for ClaimRedactor {
+    fn default() -> Self {
+        Self::new(DEFAULT_REDACTED_CLAIMS.iter().map(|claim| claim.to_string()).collect())
+    }
+}
+
+impl ClaimRedactor {
+    pub fn new(claims: Vec<String>) -> Self {
+        Self { claims }
+    }
+
+    fn is_redacted(&self, name: &str) -> bool {
+        self.claims.iter().any(|claim| name == claim || name.ends_with(&format!("/{}", claim)))
+    }
+
+    pub fn redact(&self, claims: &DynamicClaims) -> DynamicClaims {
+        DynamicClaims(claims.0.iter().map(|(name, value)| {
+            let value = if self.is_redacted(name) { Value::String(REDACTED.to_string()) } else { value.clone() };
+            (name.clone(), value)
+        }).collect())
+    }
+}
+
+// Wraps the actual logger so that no record, whatever its target, carries a token
+pub struct RedactingLogger<L: Log> {
+    inner: L,
+}
+
+impl<L: Log> RedactingLogger<L> {
+    pub fn new(inner: L) -> Self {
+        Self { inner }
+    }
+}
+
+impl<L: Log> Log for RedactingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        match redact_tokens(&message) {
+            Cow::Borrowed(_) => self.inner.log(record),
+            Cow::Owned(redacted) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{}", redacted))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build()
+            ),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::{test_claims, TestKey};
+    use jsonwebtoken::Algorithm;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_tokens() {
+        let token = TestKey::rsa("redaction", Algorithm::RS256).sign(&test_claims());
+        let signature = token.rsplit('.').next().unwrap();
+        let message = format!("Token: {:?}, again: Bearer {}.", token, token);
+        let redacted = redact_tokens(&message);
+        assert!(!redacted.contains(signature));
+        assert_eq!(redacted, format!("Token: \"<token {0}>\", again: Bearer <token {0}>.", fingerprint(&token)));
+        // nothing that merely looks dotted
+        for message in ["Discovered jwks_uri https://boto.eu.auth0.com/.well-known/jwks.json", "header.token.value", "eyJ"] {
+            assert!(matches!(redact_tokens(message), Cow::Borrowed(_)), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_token_summary() {
+        let token = TestKey::rsa("redaction", Algorithm::RS256).sign(&test_claims());
+        let summary = token_summary(&token);
+        assert!(summary.starts_with(&fingerprint(&token)));
+        assert!(summary.contains("alg: RS256"));
+        assert_eq!(fingerprint(&token).len(), "sha256:".len() + 16);
+        assert!(token_summary("garbage").ends_with("(not a JWT)"));
+    }
+
+    #[test]
+    fn test_claim_redactor() {
+        let claims: DynamicClaims = serde_json::from_value(json!({
+            "sub": "auth0|123456",
+            "email": "jane@boto.io",
+            "https://boto.io/claims/name": "Jane",
+            "https://boto.io/claims/user_id": "3e8c0f16",
+        })).unwrap();
+        let redacted = ClaimRedactor::default().redact(&claims);
+        assert_eq!(redacted.get("sub"), claims.get("sub"));
+        assert_eq!(redacted.get("email"), Some(&json!(REDACTED)));
+        assert_eq!(redacted.get("https://boto.io/claims/name"), Some(&json!(REDACTED)));
+        assert_eq!(redacted.get("https://boto.io/claims/user_id"), claims.get("https://boto.io/claims/user_id"));
+        let redacted = ClaimRedactor::new(vec!["user_id".to_string()]).redact(&claims);
+        assert_eq!(redacted.get("email"), claims.get("email"));
+        assert_eq!(redacted.get("https://boto.io/claims/user_id"), Some(&json!(REDACTED)));
+    }
+}
@@ -0,0 +1,436 @@
+use std::path::Path;
+
+use anyhow::bail;
+use fehler::throws;
+use log::debug;
+use serde_json::Value;
+
+use crate::{enums::HttpMethod, structs::{APIGatewayPolicyBuilder, DynamicClaims, RouteRule}};
+
+// Claims the granted scopes are read from: space separated strings (scope, scp)
+// or lists of strings (scp, permissions)
+pub const SCOPE_CLAIMS: [&str; 3] = ["scope", "scp", "permissions"];
+
+// Maps the scopes of a token to the routes it may call
+#[derive(Debug)]
+pub struct RouteAuthorizer {
+    rules: Vec<RouteRule>,
+}
+
+impl RouteAuthorizer {
+    #[throws(anyhow::Error)]
+    pub fn new(rules: Vec<RouteRule>) -> Self {
+        for rule in &rules {
+            if !rule.path.starts_with('/') {
+                bail!("Route path {} must start with /", rule.path)
+            }
+        }
+        check_exact(&rules.iter().map(|rule| (rule.method, rule.path.clone())).collect::<Vec<_>>())?;
+        Self { rules }
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_json(json: &str) -> Self {
+        Self::new(serde_json::from_str(json)?)?
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
+        Self::from_json(&std::fs::read_to_string(path)?)?
+    }
+
+    // Routes granted to the scopes, in configuration order and without duplicates
+    pub fn allowed_routes(&self, scopes: &[String]) -> Vec<(HttpMethod, String)> {
+        let mut routes = vec![];
+        for rule in self.rules.iter().filter(|rule| scopes.contains(&rule.scope)) {
+            if !routes.contains(&(rule.method, rule.path.clone())) {
+                routes.push((rule.method, rule.path.clone()));
+            }
+        }
+        routes
+    }
+
+    pub fn configured_routes(&self) -> Vec<(HttpMethod, String)> {
+        self.rules.iter().map(|rule| (rule.method, rule.path.clone())).collect()
+    }
+
+    pub fn denied_resources(&self, scopes: &[String]) -> Vec<(HttpMethod, String)> {
+        denied_resources(&self.allowed_routes(scopes), &self.configured_routes())
+    }
+
+    pub fn is_allowed(&self, scopes: &[String], method: HttpMethod, path: &str) -> bool {
+        is_allowed(&self.allowed_routes(scopes), method, path)
+    }
+
+    pub fn apply(&self, builder: APIGatewayPolicyBuilder, scopes: &[String]) -> APIGatewayPolicyBuilder {
+        debug!(target: "routes.apply", "Scopes: {:?}", scopes);
+        apply_routes(builder, &self.allowed_routes(scopes), &self.configured_routes())
+    }
+}
+
+pub fn is_allowed(allowed: &[(HttpMethod, String)], method: HttpMethod, path: &str) -> bool {
+    matching_route(allowed, method, path).is_some()
+}
+
+// The first allowed route that covers the call
+pub fn matching_route(allowed: &[(HttpMethod, String)], method: HttpMethod, path: &str) -> Option<(HttpMethod, String)> {
+    allowed.iter().find(|(allowed_method, pattern)| method_matches(*allowed_method, method) && path_matches(pattern, path)).cloned()
+}
+
+// Resources denied next to the allowed routes, per method:
+// - the deeper resources of the allowed routes, which their * would grant too (see deeper_resources)
+// - the resources of the configured routes that are not granted
+// In IAM an explicit deny wins over any allow, so the methods where a deny would also match
+// an allowed call are left out, and the implicit deny refuses what is not allowed there.
+pub fn denied_resources(allowed: &[(HttpMethod, String)], configured: &[(HttpMethod, String)]) -> Vec<(HttpMethod, String)> {
+    let deeper = allowed.iter().flat_map(|(method, path)| deeper_resources(path).into_iter().map(move |resource| (*method, resource)));
+    let not_granted = configured.iter().filter(|route| !allowed.contains(route)).map(|(method, path)| (*method, resource_path(path)));
+    let mut resources = vec![];
+    for (method, resource) in deeper.chain(not_granted) {
+        for method in methods(method) {
+            let grants = allowed.iter().any(|(allowed_method, path)| method_matches(*allowed_method, method) && units_overlap(&route_units(path), &resource_units(&resource)));
+            if !grants && !resources.contains(&(method, resource.clone())) {
+                resources.push((method, resource.clone()));
+            }
+        }
+    }
+    resources
+}
+
+// Allows the granted routes and explicitly denies the other configured ones
+pub fn apply_routes(mut builder: APIGatewayPolicyBuilder, allowed: &[(HttpMethod, String)], configured: &[(HttpMethod, String)]) -> APIGatewayPolicyBuilder {
+    for (method, path) in allowed {
+        for method in methods(*method) {
+            builder = builder.allow_method(method, resource_path(path));
+        }
+    }
+    for (method, resource) in denied_resources(allowed, configured) {
+        builder = builder.deny_method(method, resource);
+    }
+    builder
+}
+
+// Refuses routes the policy could not grant exactly: a deeper resource of a route (see
+// deeper_resources) that another route grants only in part can neither be denied nor allowed.
+// * and ? only stand alone in a path, IAM would read them as wildcards within a segment.
+#[throws(anyhow::Error)]
+pub fn check_exact(routes: &[(HttpMethod, String)]) {
+    for (method, path) in routes {
+        if segments(path).iter().any(|segment| *segment != "*" && (segment.contains('*') || segment.contains('?'))) {
+            bail!("Route path {} may only use * as a whole segment, and no ?", path)
+        }
+        for resource in deeper_resources(path) {
+            for (other_method, other_path) in routes {
+                let same_method = method_matches(*method, *other_method) || method_matches(*other_method, *method);
+                let (deeper, other) = (resource_units(&resource), route_units(other_path));
+                if same_method && units_overlap(&deeper, &other) && !units_cover(&other, &deeper) {
+                    bail!("Routes {} {} and {} {} cannot both be granted exactly by a policy, {} would be granted too", method, path, other_method, other_path, resource)
+                }
+            }
+        }
+    }
+}
+
+pub fn token_scopes(claims: &DynamicClaims) -> Vec<String> {
+    let mut scopes: Vec<String> = vec![];
+    for claim in SCOPE_CLAIMS {
+        let values: Vec<String> = match claims.get(claim) {
+            Some(Value::String(value)) => value.split_whitespace().map(str::to_string).collect(),
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            _ => continue,
+        };
+        for value in values {
+            if !scopes.contains(&value) {
+                scopes.push(value);
+            }
+        }
+    }
+    scopes
+}
+
+fn method_matches(pattern: HttpMethod, method: HttpMethod) -> bool {
+    pattern == HttpMethod::ALL || pattern == method
+}
+
+// In a resource ARN the * of ALL would also match the start of the path
+fn methods(method: HttpMethod) -> Vec<HttpMethod> {
+    match method {
+        HttpMethod::ALL => vec![HttpMethod::GET, HttpMethod::POST, HttpMethod::PUT, HttpMethod::DELETE, HttpMethod::PATCH, HttpMethod::HEAD, HttpMethod::OPTIONS],
+        method => vec![method],
+    }
+}
+
+// {parameter} and * match one path segment, a trailing * one or more of them
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let pattern = segments(pattern);
+    let path = segments(path);
+    for (index, segment) in pattern.iter().enumerate() {
+        if *segment == "*" && index == pattern.len() - 1 {
+            return path.len() >= pattern.len();
+        }
+        match path.get(index) {
+            Some(actual) if *segment == "*" || is_parameter(segment) || segment == actual => continue,
+            _ => return false,
+        }
+    }
+    path.len() == pattern.len()
+}
+
+fn is_parameter(segment: &str) -> bool {
+    segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.trim_matches('/').split('/').collect()
+}
+
+// IAM resource ARNs only know the * wildcard, which also matches /: the policy
+// of GET /users/{user_id} grants GET /users/42/botos too, see deeper_resources
+fn resource_path(path: &str) -> String {
+    path.split('/').map(|segment| if is_parameter(segment) { "*" } else { segment }).collect::<Vec<_>>().join("/")
+}
+
+// What resource_path grants beyond the route: the resource with one of its inner
+// wildcards spanning two segments or more, e.g. /users/*/* for /users/{user_id}.
+// A trailing * already matches one segment or more.
+fn deeper_resources(path: &str) -> Vec<String> {
+    let segments = segments(path);
+    let last = segments.len() - 1;
+    let resource: Vec<&str> = segments.iter().map(|segment| if is_parameter(segment) { "*" } else { segment }).collect();
+    (0..segments.len())
+        .filter(|index| is_parameter(segments[*index]) || (segments[*index] == "*" && *index != last))
+        .map(|index| {
+            let mut deeper = resource.clone();
+            deeper[index] = "*/*";
+            format!("/{}", deeper.join("/"))
+        })
+        .collect()
+}
+
+// A path segment of a route or of a resource
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit<'a> {
+    Literal(&'a str),
+    // exactly one segment
+    One,
+    // any number of segments, none included
+    Any,
+}
+
+// The paths a route matches, see path_matches
+fn route_units(path: &str) -> Vec<Unit<'_>> {
+    let segments = segments(path);
+    let last = segments.len() - 1;
+    segments.iter().enumerate().flat_map(|(index, segment)| match *segment {
+        "*" if index == last => vec![Unit::One, Unit::Any],
+        segment if segment == "*" || is_parameter(segment) => vec![Unit::One],
+        segment => vec![Unit::Literal(segment)],
+    }).collect()
+}
+
+// The paths an IAM resource matches, its * standing for one segment or more
+fn resource_units(resource: &str) -> Vec<Unit<'_>> {
+    segments(resource).into_iter().flat_map(|segment| match segment {
+        "*" => vec![Unit::One, Unit::Any],
+        segment => vec![Unit::Literal(segment)],
+    }).collect()
+}
+
+// Whether a path matches both
+fn units_overlap(first: &[Unit], second: &[Unit]) -> bool {
+    match (first.first(), second.first()) {
+        (None, None) => true,
+        (Some(Unit::Any), _) => units_overlap(&first[1..], second) || (!second.is_empty() && units_overlap(first, &second[1..])),
+        (_, Some(Unit::Any)) => units_overlap(first, &second[1..]) || (!first.is_empty() && units_overlap(&first[1..], second)),
+        (Some(Unit::Literal(first_segment)), Some(Unit::Literal(second_segment))) => first_segment == second_segment && units_overlap(&first[1..], &second[1..]),
+        (Some(_), Some(_)) => units_overlap(&first[1..], &second[1..]),
+        _ => false,
+    }
+}
+
+// Whether every path inner matches, outer matches too (false when unsure)
+fn units_cover(outer: &[Unit], inner: &[Unit]) -> bool {
+    match (outer.first(), inner.first()) {
+        (None, None) => true,
+        (Some(Unit::Any), _) => units_cover(&outer[1..], inner) || (!inner.is_empty() && units_cover(outer, &inner[1..])),
+        (Some(Unit::One), Some(Unit::Literal(_) | Unit::One)) => units_cover(&outer[1..], &inner[1..]),
+        (Some(Unit::Literal(outer_segment)), Some(Unit::Literal(inner_segment))) => outer_segment == inner_segment && units_cover(&outer[1..], &inner[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::policy_allows;
+    use serde_json::json;
+
+    fn authorizer() -> RouteAuthorizer {
+        RouteAuthorizer::from_json(&json!([
+            { "scope": "read:botos", "method": "GET", "path": "/botos/*" },
+            { "scope": "write:botos", "method": "POST", "path": "/botos" },
+            { "scope": "read:users", "method": "GET", "path": "/users/{user_id}" },
+            { "scope": "admin", "method": "*", "path": "/botos/{boto_id}/settings" },
+        ]).to_string()).unwrap()
+    }
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    }
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("/botos/*", "/botos/42"));
+        assert!(path_matches("/botos/*", "/botos/42/settings"));
+        assert!(!path_matches("/botos/*", "/botos"));
+        assert!(!path_matches("/botos/*", "/users/42"));
+        assert!(path_matches("/users/{user_id}", "/users/42"));
+        assert!(!path_matches("/users/{user_id}", "/users/42/botos"));
+        assert!(!path_matches("/users/{user_id}", "/users"));
+        assert!(path_matches("/*/settings", "/botos/settings"));
+        assert!(path_matches("/botos", "botos/"));
+    }
+
+    #[test]
+    fn test_token_scopes() {
+        let claims: DynamicClaims = serde_json::from_value(json!({
+            "scope": "openid read:botos",
+            "scp": ["read:botos", "write:botos"],
+            "permissions": ["read:users"],
+        })).unwrap();
+        assert_eq!(token_scopes(&claims), scopes(&["openid", "read:botos", "write:botos", "read:users"]));
+        assert!(token_scopes(&DynamicClaims::default()).is_empty());
+    }
+
+    #[test]
+    fn test_allowed_and_denied_routes() {
+        let authorizer = authorizer();
+        let granted = scopes(&["read:botos", "read:users"]);
+        assert_eq!(authorizer.allowed_routes(&granted), vec![
+            (HttpMethod::GET, "/botos/*".to_string()),
+            (HttpMethod::GET, "/users/{user_id}".to_string()),
+        ]);
+        let denied = |routes: &[(HttpMethod, &str)]| routes.iter().map(|(method, resource)| (*method, resource.to_string())).collect::<Vec<_>>();
+        // GET /botos/{boto_id}/settings is granted by GET /botos/*, the other methods are denied
+        assert_eq!(authorizer.denied_resources(&granted), denied(&[
+            (HttpMethod::GET, "/users/*/*"),
+            (HttpMethod::POST, "/botos"),
+            (HttpMethod::POST, "/botos/*/settings"),
+            (HttpMethod::PUT, "/botos/*/settings"),
+            (HttpMethod::DELETE, "/botos/*/settings"),
+            (HttpMethod::PATCH, "/botos/*/settings"),
+            (HttpMethod::HEAD, "/botos/*/settings"),
+            (HttpMethod::OPTIONS, "/botos/*/settings"),
+        ]));
+        assert!(authorizer.is_allowed(&granted, HttpMethod::GET, "/users/42"));
+        assert!(!authorizer.is_allowed(&granted, HttpMethod::DELETE, "/botos/42/settings"));
+        let allowed = authorizer.allowed_routes(&granted);
+        assert_eq!(matching_route(&allowed, HttpMethod::GET, "/botos/42"), Some((HttpMethod::GET, "/botos/*".to_string())));
+        assert_eq!(matching_route(&allowed, HttpMethod::POST, "/botos"), None);
+        assert!(authorizer.allowed_routes(&[]).is_empty());
+        assert_eq!(authorizer.denied_resources(&[]).len(), 10);
+    }
+
+    #[test]
+    fn test_apply() {
+        let builder = APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod");
+        let policy = authorizer().apply(builder, &scopes(&["read:users"])).build().unwrap();
+        let statements = serde_json::to_value(&policy.Statement).unwrap();
+        assert_eq!(policy.Statement.len(), 2);
+        assert_eq!(statements[0]["Effect"], "Allow");
+        assert_eq!(statements[0]["Resource"], json!(["arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/GET/users/*"]));
+        assert_eq!(statements[1]["Effect"], "Deny");
+        // * /botos/{boto_id}/settings per method, */botos/*/settings would match GET/users/42/botos/1/settings
+        assert_eq!(statements[1]["Resource"], json!([
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/DELETE/botos/*/settings",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/GET/botos/*",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/GET/botos/*/settings",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/GET/users/*/*",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/HEAD/botos/*/settings",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/OPTIONS/botos/*/settings",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/PATCH/botos/*/settings",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/POST/botos",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/POST/botos/*/settings",
+            "arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/PUT/botos/*/settings",
+        ]));
+    }
+
+    #[test]
+    fn test_policy_agrees_with_is_allowed() {
+        let authorizer = authorizer();
+        let granted = scopes(&["read:botos", "read:users"]);
+        let builder = APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod");
+        let policy = authorizer.apply(builder, &granted).build().unwrap();
+        let admin = scopes(&["admin", "read:users"]);
+        let admin_policy = authorizer.apply(APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod"), &admin).build().unwrap();
+        for (method, path) in [
+            (HttpMethod::GET, "/botos/42/settings"),
+            (HttpMethod::GET, "/botos/42"),
+            (HttpMethod::GET, "/users/42"),
+            (HttpMethod::GET, "/users/42/botos"),
+            (HttpMethod::GET, "/users"),
+            (HttpMethod::POST, "/botos"),
+            (HttpMethod::DELETE, "/botos/42/settings"),
+            (HttpMethod::DELETE, "/botos/42/43/settings"),
+            (HttpMethod::GET, "/users/42/botos/1/settings"),
+        ] {
+            assert_eq!(policy_allows(&policy, method, path), authorizer.is_allowed(&granted, method, path), "{} {}", method, path);
+            assert_eq!(policy_allows(&admin_policy, method, path), authorizer.is_allowed(&admin, method, path), "admin {} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn test_parameters_do_not_over_grant_in_the_policy() {
+        let authorizer = authorizer();
+        let granted = scopes(&["read:users"]);
+        let builder = APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod");
+        let policy = authorizer.apply(builder, &granted).build().unwrap();
+        assert!(policy_allows(&policy, HttpMethod::GET, "/users/42"));
+        // GET/users/* alone would match these, GET/users/*/* denies them
+        assert!(!policy_allows(&policy, HttpMethod::GET, "/users/42/botos"));
+        assert!(!policy_allows(&policy, HttpMethod::GET, "/users/42/anything/else"));
+        assert!(!policy_allows(&policy, HttpMethod::GET, "/users"));
+        // a deeper route granted along takes its calls back
+        let authorizer = RouteAuthorizer::from_json(&json!([
+            { "scope": "read:users", "method": "GET", "path": "/users/{user_id}" },
+            { "scope": "read:users", "method": "GET", "path": "/users/*" },
+        ]).to_string()).unwrap();
+        let policy = authorizer.apply(APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod"), &granted).build().unwrap();
+        assert!(policy_allows(&policy, HttpMethod::GET, "/users/42/botos/1"));
+        // every parameter gets its deeper resource
+        let authorizer = RouteAuthorizer::from_json(r#"[{ "scope": "read:settings", "method": "GET", "path": "/users/{user_id}/settings/{name}" }]"#).unwrap();
+        let granted = scopes(&["read:settings"]);
+        let policy = authorizer.apply(APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef", "prod"), &granted).build().unwrap();
+        assert!(policy_allows(&policy, HttpMethod::GET, "/users/42/settings/theme"));
+        assert!(!policy_allows(&policy, HttpMethod::GET, "/users/42/settings/theme/dark"));
+        assert!(!policy_allows(&policy, HttpMethod::GET, "/users/42/43/settings/theme"));
+    }
+
+    #[test]
+    fn test_routes_the_policy_cannot_grant_exactly() {
+        // GET/users/*/* would deny GET /users/42/botos, GET/users/* alone grants GET /users/42/else
+        let error = RouteAuthorizer::from_json(&json!([
+            { "scope": "read:users", "method": "GET", "path": "/users/{user_id}" },
+            { "scope": "read:botos", "method": "GET", "path": "/users/{user_id}/botos" },
+        ]).to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "Routes GET /users/{user_id} and GET /users/{user_id}/botos cannot both be granted exactly by a policy, /users/*/* would be granted too");
+        assert!(RouteAuthorizer::from_json(&json!([
+            { "scope": "read:users", "method": "*", "path": "/users/{user_id}" },
+            { "scope": "write:botos", "method": "POST", "path": "/users/{user_id}/botos" },
+        ]).to_string()).is_err());
+        // different methods never meet
+        assert!(RouteAuthorizer::from_json(&json!([
+            { "scope": "read:users", "method": "GET", "path": "/users/{user_id}" },
+            { "scope": "write:botos", "method": "POST", "path": "/users/{user_id}/botos" },
+        ]).to_string()).is_ok());
+        assert!(RouteAuthorizer::from_json(r#"[{ "scope": "read", "method": "GET", "path": "/botos/b*" }]"#).is_err());
+        assert!(RouteAuthorizer::from_json(r#"[{ "scope": "read", "method": "GET", "path": "/botos/?" }]"#).is_err());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(RouteAuthorizer::from_json(r#"[{ "scope": "read", "method": "GET", "path": "botos" }]"#).is_err());
+        assert!(RouteAuthorizer::from_json(r#"[{ "scope": "read", "method": "FETCH", "path": "/botos" }]"#).is_err());
+    }
+}
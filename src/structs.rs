@@ -1,19 +1,42 @@
-use std::{error::Error, fmt};
+use std::{collections::{BTreeMap, HashMap}, error::Error, fmt, str::FromStr};
+use anyhow::bail;
+use fehler::throws;
+use jsonwebtoken::{Algorithm, DecodingKey};
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
-use crate::enums::{Effect, HttpMethod, KeyAlgorithm, KeyType, StringOrArray};
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Claims {
-    pub sub: String,
-    pub iss: String,
-    pub aud: StringOrArray,
-    pub iat: usize,
-    pub exp: usize,
-    pub azp: String,
-    pub gty: Option<String>,
-    #[serde(rename = "https://boto.io/claims/user_id")]
-    pub user_id: String,
+use serde_json::{Map, Value};
+use tracing::{field, info_span};
+use crate::{arn::MethodArn, telemetry, enums::{AudienceMatch, ContextEncoding, Effect, EllipticCurve, FailureCategory, HttpMethod, KeyAlgorithm, KeyType, StringOrArray, TokenSource}, utils};
+
+// The whole token payload, for issuers whose claims are not known in advance.
+// Any Deserialize type can be used instead, see Auth::validate_token_as.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct DynamicClaims(pub Map<String, Value>);
+
+impl DynamicClaims {
+    // null is treated like a missing claim
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name).filter(|value| !value.is_null())
+    }
+
+    // Custom claims are usually namespaced (https://example.com/claims/user_id),
+    // the plain name wins when both are present
+    pub fn claim(&self, name: &str, namespace: Option<&str>) -> Option<&Value> {
+        self.get(name).or_else(|| namespace.and_then(|namespace| self.get(&format!("{}{}", namespace, name))))
+    }
+
+    // strings as they are, numbers and booleans formatted
+    pub fn claim_string(&self, name: &str, namespace: Option<&str>) -> Option<String> {
+        match self.claim(name, namespace)? {
+            Value::String(value) => Some(value.clone()),
+            value @ (Value::Number(_) | Value::Bool(_)) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn sub(&self) -> Option<&str> {
+        self.get("sub").and_then(Value::as_str)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -21,10 +44,219 @@ pub struct JWK {
     pub kty: KeyType,
     pub alg: Option<KeyAlgorithm>,
     pub kid: Option<String>,
-    // Shared modulus
-    pub n: String,
-    // Public key exponent
-    pub e: String,
+    // Shared modulus (RSA)
+    pub n: Option<String>,
+    // Public key exponent (RSA)
+    pub e: Option<String>,
+    // Curve (EC and OKP)
+    pub crv: Option<EllipticCurve>,
+    // Public key x coordinate (EC) or the public key itself (OKP)
+    pub x: Option<String>,
+    // Public key y coordinate (EC)
+    pub y: Option<String>,
+    #[serde(rename = "use")]
+    pub key_use: Option<String>,
+    pub key_ops: Option<Vec<String>>,
+    // X.509 certificate chain, leaf first (base64, not base64url)
+    pub x5c: Option<Vec<String>>,
+    // SHA-1 thumbprint of the leaf certificate
+    pub x5t: Option<String>,
+    // SHA-256 thumbprint of the leaf certificate
+    #[serde(rename = "x5t#S256")]
+    pub x5t_s256: Option<String>,
+}
+
+impl JWK {
+    // Guards against algorithm confusion: the algorithm picked by the token
+    // header has to match the key type, the curve and the alg published
+    // alongside the key (when there is one).
+    pub fn supports(&self, algorithm: Algorithm) -> bool {
+        if let Some(alg) = &self.alg {
+            if alg.algorithm() != algorithm {
+                return false;
+            }
+        }
+        match (&self.kty, algorithm) {
+            (KeyType::RSA, Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512) => true,
+            (KeyType::RSA, Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512) => true,
+            (KeyType::EC, Algorithm::ES256) => self.crv == Some(EllipticCurve::P256),
+            (KeyType::EC, Algorithm::ES384) => self.crv == Some(EllipticCurve::P384),
+            (KeyType::OKP, Algorithm::EdDSA) => self.crv == Some(EllipticCurve::Ed25519),
+            _ => false,
+        }
+    }
+
+    // Keys published for encryption, or restricted to operations other than
+    // "verify", must not be used to check signatures (RFC 7517, section 4.3)
+    #[throws(anyhow::Error)]
+    pub fn check_signature_use(&self) {
+        if let Some(key_use) = &self.key_use {
+            if key_use != "sig" {
+                bail!("Key use is {}, not sig", key_use)
+            }
+        }
+        if let Some(key_ops) = &self.key_ops {
+            if !key_ops.iter().any(|op| op == "verify") {
+                bail!("Key operations {:?} do not include verify", key_ops)
+            }
+        }
+        self.decoding_key()?;
+    }
+
+    #[throws(anyhow::Error)]
+    pub fn decoding_key(&self) -> DecodingKey {
+        match (&self.kty, &self.n, &self.e, &self.x, &self.y) {
+            (KeyType::RSA, Some(n), Some(e), _, _) => DecodingKey::from_rsa_components(n, e)?,
+            (KeyType::EC, _, _, Some(x), Some(y)) => DecodingKey::from_ec_components(x, y)?,
+            (KeyType::OKP, _, _, Some(x), _) => DecodingKey::from_ed_components(x)?,
+            _ => bail!("Key {:?} is missing the public key components for {:?}", self.kid, self.kty),
+        }
+    }
+}
+
+// Result of loading a JWKS: the keys we can verify signatures with, and the
+// ones that were skipped
+#[derive(Clone, Debug, Default)]
+pub struct KeySet {
+    pub accepted: Vec<JWK>,
+    pub rejected: Vec<RejectedKey>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RejectedKey {
+    pub kid: Option<String>,
+    pub kty: Option<String>,
+    pub reason: String,
+}
+
+// One trusted issuer, as listed in JWTAUTH_ISSUERS
+#[derive(Clone, Debug, Deserialize)]
+pub struct IssuerConfig {
+    pub issuer: String,
+    // a single audience or a list of them
+    pub audience: StringOrArray,
+    #[serde(default)]
+    pub audience_match: AudienceMatch,
+    // discovered from the issuer when missing
+    pub keys_repo: Option<String>,
+    // comma separated, see utils::parse_algorithms
+    pub algorithms: Option<String>,
+    // path to a PEM bundle, see certificates::TrustAnchors
+    pub x5c_trust_anchors: Option<String>,
+    #[serde(default)]
+    pub required_claims: Vec<String>,
+    // prefix of the custom claims, e.g. https://example.com/claims/
+    pub claims_namespace: Option<String>,
+    #[serde(default = "default_principal_claim")]
+    pub principal_claim: String,
+    #[serde(default)]
+    pub time_validation: TimeValidation,
+}
+
+// Grants the routes matching method and path to tokens carrying scope.
+// path may contain {parameters} and * wildcards, see routes::RouteAuthorizer
+#[derive(Clone, Debug, Deserialize)]
+pub struct RouteRule {
+    pub scope: String,
+    pub method: HttpMethod,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    pub method: HttpMethod,
+    pub path: String,
+}
+
+// Routes of a role, on top of the ones of the roles it inherits
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RoleConfig {
+    #[serde(default)]
+    pub inherits: Vec<String>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RolesConfig {
+    // e.g. https://boto.io/claims/roles, cognito:groups or realm_access.roles
+    pub roles_claim: String,
+    pub roles: HashMap<String, RoleConfig>,
+}
+
+// Puts a claim of valid tokens in the authorizer context, see context::ContextMapping
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContextClaim {
+    // a claim name, namespaced or not, or a JSON pointer into the claims like /address/country
+    pub claim: String,
+    // the claim name, or the last segment of the pointer, when missing
+    pub key: Option<String>,
+    #[serde(default)]
+    pub encoding: ContextEncoding,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContextConfig {
+    pub claims: Vec<ContextClaim>,
+    // longer strings are left out of the context
+    #[serde(default = "default_max_context_value_length")]
+    pub max_value_length: usize,
+    // of the whole context, serialized
+    #[serde(default = "default_max_context_size")]
+    pub max_size: usize,
+}
+
+pub fn default_max_context_value_length() -> usize {
+    1024
+}
+
+pub fn default_max_context_size() -> usize {
+    4096
+}
+
+pub fn default_principal_claim() -> String {
+    "sub".to_string()
+}
+
+// Timing checks, all values in seconds
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TimeValidation {
+    // clock skew tolerated on exp, nbf and iat
+    pub leeway: u64,
+    pub require_nbf: bool,
+    // tokens issued (iat) longer ago than this are refused
+    pub max_age: Option<u64>,
+    // tokens valid (exp - iat) for longer than this are refused
+    pub max_lifetime: Option<u64>,
+}
+
+// Provider metadata served at {issuer}/.well-known/openid-configuration
+#[derive(Clone, Debug, Deserialize)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub userinfo_endpoint: Option<String>,
+}
+
+impl OpenIdConfiguration {
+    // Only asymmetric algorithms are kept: HS* would let anyone holding the
+    // (public) JWK sign tokens, and "none" is never accepted.
+    #[throws(anyhow::Error)]
+    pub fn signing_algorithms(&self) -> Vec<Algorithm> {
+        let algorithms: Vec<Algorithm> = self.id_token_signing_alg_values_supported.iter()
+            .filter_map(|alg| Algorithm::from_str(alg).ok())
+            .filter(|alg| !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+            .collect();
+        if algorithms.is_empty() {
+            bail!("No supported signing algorithm advertised by {}", self.issuer)
+        }
+        algorithms
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -43,15 +275,36 @@ pub struct LambdaError {
 
 // Error doesn't require you to implement any methods, but
 // your type must also implement Debug and Display.
-impl<'a> Error for LambdaError {}
+impl Error for LambdaError {}
 
-impl<'a> fmt::Display for LambdaError {
+impl fmt::Display for LambdaError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Delegate to the Display impl for `&str`:
         self.msg.fmt(f)
     }
 }
 
+// A refused request whose category is known where it is raised
+#[derive(Debug, Clone)]
+pub struct AuthError {
+    pub category: FailureCategory,
+    pub msg: String,
+}
+
+impl AuthError {
+    pub fn new<T: Into<String>>(category: FailureCategory, msg: T) -> Self {
+        Self { category, msg: msg.into() }
+    }
+}
+
+impl Error for AuthError {}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.msg.fmt(f)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct APIGatewayCustomAuthorizerResponse {
@@ -67,15 +320,20 @@ pub struct APIGatewayCustomAuthorizerPolicy {
     pub Statement: Vec<IAMPolicyStatement>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// e.g. {"IpAddress": {"aws:SourceIp": ["203.0.113.0/24"]}}, sorted so the output is stable
+pub type IAMPolicyCondition = BTreeMap<String, BTreeMap<String, Value>>;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[allow(non_snake_case)]
 pub struct IAMPolicyStatement {
     pub Action: Vec<String>,
     pub Effect: Effect,
     pub Resource: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Condition: Option<IAMPolicyCondition>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct APIGatewayCustomAuthorizerRequest {
     #[serde(rename = "type")]
@@ -84,12 +342,165 @@ pub struct APIGatewayCustomAuthorizerRequest {
     pub method_arn: String,
 }
 
+// Payload of REQUEST authorizers, whatever API Gateway has no value for is null
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct APIGatewayRequestAuthorizerRequest {
+    #[serde(rename = "type")]
+    pub _type: String,
+    pub method_arn: String,
+    pub resource: Option<String>,
+    pub path: Option<String>,
+    pub http_method: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    pub multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub multi_value_query_string_parameters: Option<HashMap<String, Vec<String>>>,
+    pub path_parameters: Option<HashMap<String, String>>,
+    pub stage_variables: Option<HashMap<String, String>>,
+    pub request_context: Option<APIGatewayRequestContext>,
+}
+
+// Header, cookie and query string lookups of the REQUEST payloads
+pub trait RequestValues {
+    fn header(&self, name: &str) -> Option<&str>;
+    fn cookie(&self, name: &str) -> Option<&str>;
+    fn query_parameter(&self, name: &str) -> Option<&str>;
+
+    // The first token found in the sources, in order
+    fn token(&self, sources: &[TokenSource]) -> Option<String> {
+        sources.iter().find_map(|source| match source {
+            TokenSource::Header(name) => self.header(name).and_then(|value| utils::bearer_token(value).ok()),
+            TokenSource::Cookie(name) => self.cookie(name).filter(|value| !value.is_empty()).map(str::to_string),
+            TokenSource::Query(name) => self.query_parameter(name).filter(|value| !value.is_empty()).map(str::to_string),
+        })
+    }
+}
+
+fn find_cookie<'a, I: Iterator<Item = &'a str>>(cookies: I, name: &str) -> Option<&'a str> {
+    cookies.flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+impl RequestValues for APIGatewayRequestAuthorizerRequest {
+    // Header names are case insensitive
+    fn header(&self, name: &str) -> Option<&str> {
+        let single = self.headers.iter().flatten()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+        single.or_else(|| {
+            self.multi_value_headers.iter().flatten()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .and_then(|(_, values)| values.first().map(String::as_str))
+        })
+    }
+
+    fn cookie(&self, name: &str) -> Option<&str> {
+        find_cookie(self.header("Cookie").into_iter(), name)
+    }
+
+    fn query_parameter(&self, name: &str) -> Option<&str> {
+        match self.query_string_parameters.as_ref().and_then(|parameters| parameters.get(name)) {
+            Some(value) => Some(value.as_str()),
+            None => self.multi_value_query_string_parameters.as_ref()
+                .and_then(|parameters| parameters.get(name))
+                .and_then(|values| values.first().map(String::as_str)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct APIGatewayRequestContext {
+    pub account_id: Option<String>,
+    pub api_id: Option<String>,
+    pub stage: Option<String>,
+    pub request_id: Option<String>,
+    pub http_method: Option<String>,
+    pub resource_path: Option<String>,
+    pub path: Option<String>,
+    pub identity: Option<Value>,
+}
+
+// Payload format 2.0 of HTTP API authorizers
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpApiAuthorizerRequest {
+    pub version: String,
+    #[serde(rename = "type")]
+    pub _type: String,
+    pub route_arn: String,
+    pub identity_source: Option<Vec<String>>,
+    pub route_key: Option<String>,
+    pub raw_path: Option<String>,
+    pub raw_query_string: Option<String>,
+    pub cookies: Option<Vec<String>>,
+    // lower case names, repeated headers are joined with commas
+    pub headers: Option<HashMap<String, String>>,
+    // repeated parameters are joined with commas
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub path_parameters: Option<HashMap<String, String>>,
+    pub stage_variables: Option<HashMap<String, String>>,
+    pub request_context: Option<HttpApiRequestContext>,
+}
+
+impl RequestValues for HttpApiAuthorizerRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().flatten()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn cookie(&self, name: &str) -> Option<&str> {
+        find_cookie(self.cookies.iter().flatten().map(String::as_str), name)
+    }
+
+    fn query_parameter(&self, name: &str) -> Option<&str> {
+        self.query_string_parameters.as_ref().and_then(|parameters| parameters.get(name)).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpApiRequestContext {
+    pub account_id: Option<String>,
+    pub api_id: Option<String>,
+    pub domain_name: Option<String>,
+    pub request_id: Option<String>,
+    pub route_key: Option<String>,
+    pub stage: Option<String>,
+    pub http: Option<HttpApiRequestContextHttp>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpApiRequestContextHttp {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub protocol: Option<String>,
+    pub source_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// Simple response of HTTP API authorizers, a 403 when not authorized
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpApiSimpleResponse {
+    pub is_authorized: bool,
+    pub context: Value,
+}
+
 pub struct APIGatewayPolicyBuilder {
+    pub partition: String,
     pub region: String,
     pub aws_account_id: String,
     pub rest_api_id: String,
     pub stage: String,
     pub policy: APIGatewayCustomAuthorizerPolicy,
+    // invalid resources, reported by build
+    errors: Vec<String>,
 }
 
 impl APIGatewayPolicyBuilder {
@@ -100,6 +511,7 @@ impl APIGatewayPolicyBuilder {
         stage: &str,
     ) -> APIGatewayPolicyBuilder {
         Self {
+            partition: "aws".to_string(),
             region: region.to_string(),
             aws_account_id: account_id.to_string(),
             rest_api_id: api_id.to_string(),
@@ -108,33 +520,61 @@ impl APIGatewayPolicyBuilder {
                 Version: "2012-10-17".to_string(),
                 Statement: vec![],
             },
+            errors: vec![],
         }
     }
 
+    // Policies for the API and stage of the method being authorized
+    pub fn from_method_arn(arn: &MethodArn) -> APIGatewayPolicyBuilder {
+        Self::new(&arn.region, &arn.account_id, &arn.api_id, &arn.stage).with_partition(&arn.partition)
+    }
+
+    pub fn with_partition(mut self, partition: &str) -> Self {
+        self.partition = partition.to_string();
+        self
+    }
+
     pub fn add_method<T: Into<String>>(
+        self,
+        effect: Effect,
+        method: HttpMethod,
+        resource: T,
+    ) -> Self {
+        self.add_method_with_conditions(effect, method, resource, None)
+    }
+
+    pub fn add_method_with_conditions<T: Into<String>>(
         mut self,
         effect: Effect,
         method: HttpMethod,
         resource: T,
+        conditions: Option<IAMPolicyCondition>,
     ) -> Self {
+        let resource = resource.into();
+        if let Err(error) = utils::validate_resource_path(&resource) {
+            self.errors.push(error.to_string());
+            return self;
+        }
         let m = match method {
             HttpMethod::ALL => "*".to_string(),
             _ => method.to_string()
         };
         let resource_arn = format!(
-            "arn:aws:execute-api:{}:{}:{}/{}/{}/{}",
+            "arn:{}:execute-api:{}:{}:{}/{}/{}/{}",
+            &self.partition,
             &self.region,
             &self.aws_account_id,
             &self.rest_api_id,
             &self.stage,
             m,
-            resource.into().trim_start_matches("/")
+            resource.trim_start_matches('/')
         );
 
         let stmt = IAMPolicyStatement {
             Effect: effect,
             Action: vec!["execute-api:Invoke".to_string()],
             Resource: vec![resource_arn],
+            Condition: conditions,
         };
 
         self.policy.Statement.push(stmt);
@@ -144,21 +584,62 @@ impl APIGatewayPolicyBuilder {
     pub fn allow_all_methods(self) -> Self {
         self.add_method(Effect::Allow, HttpMethod::ALL, "*")
     }
-    
-    // pub fn deny_all_methods(self) -> Self {
-    //     self.add_method(Effect::Deny, HttpMethod::ALL, "*")
-    // }
 
-    // pub fn allow_method(self, method: HttpMethod, resource: String) -> Self {
-    //     self.add_method(Effect::Allow, method, resource)
-    // }
+    pub fn deny_all_methods(self) -> Self {
+        self.add_method(Effect::Deny, HttpMethod::ALL, "*")
+    }
+
+    pub fn allow_method(self, method: HttpMethod, resource: String) -> Self {
+        self.add_method(Effect::Allow, method, resource)
+    }
 
     pub fn deny_method(self, method: HttpMethod, resource: String) -> Self {
         self.add_method(Effect::Deny, method, resource)
     }
 
-    // Creates and executes a new child thread.
+    pub fn allow_method_with_conditions(self, method: HttpMethod, resource: String, conditions: IAMPolicyCondition) -> Self {
+        self.add_method_with_conditions(Effect::Allow, method, resource, Some(conditions))
+    }
+
+    pub fn deny_method_with_conditions(self, method: HttpMethod, resource: String, conditions: IAMPolicyCondition) -> Self {
+        self.add_method_with_conditions(Effect::Deny, method, resource, Some(conditions))
+    }
+
+    // Statements with the same effect and conditions are merged into one. Allow
+    // statements come first, resources are sorted, so equal inputs give equal output.
+    #[throws(anyhow::Error)]
     pub fn build(self) -> APIGatewayCustomAuthorizerPolicy {
-        self.policy
+        let span = info_span!("build_policy", statements = self.policy.Statement.len() as u64, outcome = field::Empty, error = field::Empty);
+        let _entered = span.enter();
+        let policy = self.merge();
+        telemetry::record_outcome(&span, &policy);
+        policy?
     }
-}
\ No newline at end of file
+
+    // Statements with the same effect, action and condition become one
+    #[throws(anyhow::Error)]
+    fn merge(self) -> APIGatewayCustomAuthorizerPolicy {
+        if !self.errors.is_empty() {
+            bail!("Invalid policy: {}", self.errors.join(", "))
+        }
+        let mut statements: Vec<IAMPolicyStatement> = vec![];
+        for statement in self.policy.Statement {
+            let same = |merged: &&mut IAMPolicyStatement| {
+                merged.Effect == statement.Effect && merged.Action == statement.Action && merged.Condition == statement.Condition
+            };
+            match statements.iter_mut().find(same) {
+                Some(merged) => merged.Resource.extend(statement.Resource),
+                None => statements.push(statement),
+            }
+        }
+        for statement in &mut statements {
+            statement.Resource.sort();
+            statement.Resource.dedup();
+        }
+        statements.sort_by_cached_key(|statement| (statement.Effect, serde_json::to_string(&statement.Condition).unwrap_or_default()));
+        APIGatewayCustomAuthorizerPolicy {
+            Version: self.policy.Version,
+            Statement: statements,
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::bail;
+use fehler::throws;
+use log::debug;
+use opentelemetry::{
+    trace::{SpanContext, SpanId, Status, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider},
+    Context,
+};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::filter_fn, registry::LookupSpan, Layer};
+
+// the collector is given this long to take the spans, the authorizer answers anyway
+pub const EXPORT_TIMEOUT: Duration = Duration::from_millis(300);
+
+// Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1
+#[derive(Debug, Clone, PartialEq)]
+pub struct XRayTraceHeader {
+    pub root: String,
+    pub parent: Option<String>,
+    pub sampled: Option<bool>,
+}
+
+impl FromStr for XRayTraceHeader {
+    type Err = anyhow::Error;
+
+    #[throws(anyhow::Error)]
+    fn from_str(header: &str) -> Self {
+        let (mut root, mut parent, mut sampled) = (None, None, None);
+        for (key, value) in header.split(';').filter_map(|part| part.trim().split_once('=')) {
+            match key {
+                "Root" => root = Some(value.to_string()),
+                "Parent" => parent = Some(value.to_string()),
+                "Sampled" => sampled = Some(value == "1"),
+                // Lineage and whatever comes next
+                _ => {},
+            }
+        }
+        match root {
+            Some(root) => Self { root, parent, sampled },
+            None => bail!("X-Ray trace header {:?} has no Root", header),
+        }
+    }
+}
+
+impl XRayTraceHeader {
+    // 1-<8 hex digits of epoch seconds>-<24 hex digits>, an OpenTelemetry trace id once the dashes are gone
+    #[throws(anyhow::Error)]
+    pub fn trace_id(&self) -> String {
+        let trace_id = match self.root.split('-').collect::<Vec<_>>()[..] {
+            ["1", time, random] if time.len() == 8 && random.len() == 24 => format!("{}{}", time, random),
+            _ => bail!("Unsupported X-Ray trace id {}", self.root),
+        };
+        if !trace_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Unsupported X-Ray trace id {}", self.root)
+        }
+        trace_id.to_ascii_lowercase()
+    }
+
+    pub fn parent_span_id(&self) -> Option<String> {
+        self.parent.clone().filter(|parent| parent.len() == 16 && parent.chars().all(|c| c.is_ascii_hexdigit()))
+    }
+
+    // The Lambda segment, as the remote parent of the spans of the invocation.
+    // Only Sampled=0 leaves the trace out.
+    pub fn span_context(&self) -> Option<SpanContext> {
+        let trace_id = TraceId::from_hex(&self.trace_id().ok()?).ok()?;
+        let parent = SpanId::from_hex(&self.parent_span_id()?).ok()?;
+        let flags = if self.sampled == Some(false) { TraceFlags::default() } else { TraceFlags::SAMPLED };
+        Some(SpanContext::new(trace_id, parent, flags, true, TraceState::default()))
+    }
+}
+
+// Makes the span, before it is entered, a child of the X-Ray segment of the invocation
+pub fn continue_xray_trace(span: &Span, header: &str) {
+    match header.parse::<XRayTraceHeader>().ok().and_then(|header| header.span_context()) {
+        Some(parent) => {
+            let _ = span.set_parent(Context::new().with_remote_span_context(parent));
+        },
+        None => debug!(target: "telemetry.continue_xray_trace", "No trace to continue in {:?}, starting a new one", header),
+    }
+}
+
+// Records the outcome of the work done in a span, for both successes and failures
+pub fn record_outcome<T>(span: &Span, result: &anyhow::Result<T>) {
+    match result {
+        Ok(_) => {
+            span.record("outcome", "ok");
+        },
+        Err(error) => {
+            span.record("outcome", "error").record("error", tracing::field::display(error.root_cause()));
+            span.set_status(Status::error(error.root_cause().to_string()));
+        },
+    }
+}
+
+// Sends the spans of the crate to an OpenTelemetry collector, in OTLP/HTTP JSON
+pub struct OtlpExporter {
+    pub endpoint: String,
+    pub service_name: String,
+    provider: SdkTracerProvider,
+}
+
+impl OtlpExporter {
+    // e.g. http://localhost:4318, the traces are posted to /v1/traces
+    #[throws(anyhow::Error)]
+    pub fn new(endpoint: String, service_name: String) -> Self {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name.clone()).build())
+            .build();
+        Self { endpoint, service_name, provider }
+    }
+
+    // Spans of other crates (reqwest, hyper...) are not ours to export
+    pub fn layer<S: Subscriber + for<'a> LookupSpan<'a>>(&self) -> impl Layer<S> {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.provider.tracer(env!("CARGO_PKG_NAME")))
+            .with_filter(filter_fn(|metadata| metadata.target().starts_with(env!("CARGO_PKG_NAME"))))
+    }
+
+    // The Lambda may be frozen as soon as it answers, so this runs before.
+    // The export blocks for up to EXPORT_TIMEOUT, away from the handler.
+    #[throws(anyhow::Error)]
+    pub async fn flush(&self) {
+        let provider = self.provider.clone();
+        tokio::task::spawn_blocking(move || provider.force_flush()).await??;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const HEADER: &str = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    #[test]
+    fn test_parse_xray_header() {
+        let header: XRayTraceHeader = HEADER.parse().unwrap();
+        assert_eq!(header.trace_id().unwrap(), "5759e988bd862e3fe1be46a994272793");
+        assert_eq!(header.parent_span_id().as_deref(), Some("53995c3f42cd8ad8"));
+        assert_eq!(header.sampled, Some(true));
+        let header: XRayTraceHeader = "Root=1-5759e988-bd862e3fe1be46a994272793;Lineage=a87bd80c:0".parse().unwrap();
+        assert_eq!((header.parent, header.sampled), (None, None));
+        assert!("Parent=53995c3f42cd8ad8".parse::<XRayTraceHeader>().is_err());
+        assert!("Root=2-5759e988-bd862e3f".parse::<XRayTraceHeader>().unwrap().trace_id().is_err());
+    }
+
+    #[test]
+    fn test_span_context() {
+        let parent = HEADER.parse::<XRayTraceHeader>().unwrap().span_context().unwrap();
+        assert_eq!(parent.trace_id().to_string(), "5759e988bd862e3fe1be46a994272793");
+        assert_eq!(parent.span_id().to_string(), "53995c3f42cd8ad8");
+        assert!(parent.is_sampled() && parent.is_remote());
+        let unsampled: XRayTraceHeader = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=0".parse().unwrap();
+        assert!(!unsampled.span_context().unwrap().is_sampled());
+        // no parent to attach the spans to
+        let header: XRayTraceHeader = "Root=1-5759e988-bd862e3fe1be46a994272793".parse().unwrap();
+        assert!(header.span_context().is_none());
+    }
+}
@@ -0,0 +1,104 @@
+// Locally generated signing keys and the claims they sign, so that no test depends on a
+// live IdP. Only external crates here: tests/common includes this file as is.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::PKey,
+    rsa::Rsa,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+pub struct TestKey {
+    // as published in a JWKS
+    pub jwk: Value,
+    pub encoding_key: EncodingKey,
+    pub algorithm: Algorithm,
+}
+
+pub fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl TestKey {
+    pub fn rsa(kid: &str, algorithm: Algorithm) -> Self {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwk = json!({
+            "kty": "RSA",
+            "kid": kid,
+            "n": base64url(&rsa.n().to_vec()),
+            "e": base64url(&rsa.e().to_vec()),
+        });
+        let encoding_key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        Self { jwk, encoding_key, algorithm }
+    }
+
+    pub fn ec(kid: &str, algorithm: Algorithm) -> Self {
+        let (nid, crv, size) = match algorithm {
+            Algorithm::ES384 => (Nid::SECP384R1, "P-384", 48),
+            _ => (Nid::X9_62_PRIME256V1, "P-256", 32),
+        };
+        let group = EcGroup::from_curve_name(nid).unwrap();
+        let ec = EcKey::generate(&group).unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        ec.public_key().affine_coordinates(&group, &mut x, &mut y, &mut BigNumContext::new().unwrap()).unwrap();
+        let jwk = json!({
+            "kty": "EC",
+            "kid": kid,
+            "crv": crv,
+            "x": base64url(&x.to_vec_padded(size).unwrap()),
+            "y": base64url(&y.to_vec_padded(size).unwrap()),
+        });
+        let pem = PKey::from_ec_key(ec).unwrap().private_key_to_pem_pkcs8().unwrap();
+        Self { jwk, encoding_key: EncodingKey::from_ec_pem(&pem).unwrap(), algorithm }
+    }
+
+    pub fn ed(kid: &str) -> Self {
+        let key = PKey::generate_ed25519().unwrap();
+        let jwk = json!({
+            "kty": "OKP",
+            "kid": kid,
+            "crv": "Ed25519",
+            "x": base64url(&key.raw_public_key().unwrap()),
+        });
+        let encoding_key = EncodingKey::from_ed_pem(&key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        Self { jwk, encoding_key, algorithm: Algorithm::EdDSA }
+    }
+
+    // The public key, as a JWK struct or a JSON value
+    pub fn jwk<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_value(self.jwk.clone()).unwrap()
+    }
+
+    pub fn sign(&self, claims: &Value) -> String {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.jwk["kid"].as_str().map(str::to_string);
+        encode(&header, claims, &self.encoding_key).unwrap()
+    }
+}
+
+pub fn jwks(keys: &[&TestKey]) -> String {
+    json!({ "keys": keys.iter().map(|key| key.jwk.clone()).collect::<Vec<_>>() }).to_string()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Valid for an hour
+pub fn claims(issuer: &str, audience: &str) -> Value {
+    json!({
+        "iss": issuer,
+        "sub": "auth0|123456",
+        "aud": audience,
+        "iat": now(),
+        "exp": now() + 3600,
+        "azp": "client-id",
+        "https://boto.io/claims/user_id": "3e8c0f16-a5b8-44e7-a9d2-da95eb63f4f5",
+    })
+}
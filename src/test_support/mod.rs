@@ -0,0 +1,71 @@
+// Fixtures shared by the unit tests of the modules
+// shared with tests/common, which uses all of it
+#[allow(dead_code)]
+mod keys;
+
+pub(crate) use keys::*;
+
+use openssl::{
+    asn1::{Asn1Integer, Asn1Time},
+    bn::BigNum,
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    x509::{extension::BasicConstraints, X509Name, X509},
+};
+use serde_json::{json, Value};
+
+use crate::{enums::{Effect, HttpMethod}, structs::APIGatewayCustomAuthorizerPolicy};
+
+pub(crate) const TEST_AUDIENCE: &str = "https://api.example.com";
+pub(crate) const TEST_ISSUER: &str = "https://issuer.example.com/";
+
+// Issued by TEST_ISSUER for TEST_AUDIENCE and its userinfo endpoint
+pub(crate) fn test_claims() -> Value {
+    let mut claims = claims(TEST_ISSUER, TEST_AUDIENCE);
+    claims["aud"] = json!([TEST_AUDIENCE, "https://issuer.example.com/userinfo"]);
+    claims
+}
+
+// validity is given in days relative to now
+pub(crate) fn certificate(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, ca: bool, validity: (i64, i64)) -> X509 {
+    let now = now() as i64;
+    let mut subject = X509Name::builder().unwrap();
+    subject.append_entry_by_text("CN", name).unwrap();
+    let subject = subject.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(1).unwrap()).unwrap()).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder.set_not_before(&Asn1Time::from_unix(now + validity.0 * 86400).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::from_unix(now + validity.1 * 86400).unwrap()).unwrap();
+    if ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    }
+    let (issuer_name, signing_key) = match issuer {
+        Some((issuer, issuer_key)) => (issuer.subject_name().to_owned().unwrap(), issuer_key),
+        None => (subject, key),
+    };
+    builder.set_issuer_name(&issuer_name).unwrap();
+    builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+// IAM resource matching, * matches any characters, / included
+fn iam_matches(resource: &[u8], arn: &[u8]) -> bool {
+    match (resource.first(), arn.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => iam_matches(&resource[1..], arn) || (!arn.is_empty() && iam_matches(resource, &arn[1..])),
+        (Some(resource_char), Some(arn_char)) => resource_char == arn_char && iam_matches(&resource[1..], &arn[1..]),
+        _ => false,
+    }
+}
+
+// IAM evaluation: an explicit deny wins, then any allow
+pub(crate) fn policy_allows(policy: &APIGatewayCustomAuthorizerPolicy, method: HttpMethod, path: &str) -> bool {
+    let arn = format!("arn:aws:execute-api:eu-west-1:123456789012:abcdef/prod/{}{}", method, path);
+    let matches = |effect: Effect| policy.Statement.iter()
+        .filter(|statement| statement.Effect == effect)
+        .any(|statement| statement.Resource.iter().any(|resource| iam_matches(resource.as_bytes(), arn.as_bytes())));
+    !matches(Effect::Deny) && matches(Effect::Allow)
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use aws_config::{timeout::TimeoutConfig, BehaviorVersion};
+use aws_sdk_dynamodb::{error::DisplayErrorContext, types::AttributeValue, Client};
+use fehler::throws;
+use log::{debug, warn};
+use tracing::{field, info_span, Instrument};
+
+use crate::{enums::{DirectoryFailureMode, FailureCategory}, metrics, structs::AuthError, telemetry};
+
+pub const DEFAULT_USERS_CACHE_TTL: Duration = Duration::from_secs(60);
+// the authorizer answers within the Lambda timeout, even when the table does not
+pub const DEFAULT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Tells whether the principal of a valid token may call the API
+#[async_trait]
+pub trait UserDirectory: Send + Sync {
+    // Err when the directory cannot tell
+    async fn is_blocked(&self, principal: &str) -> anyhow::Result<bool>;
+}
+
+// Blocked principals listed as items of a DynamoDB table, keyed by principal id
+// with a boolean attribute. Principals without an item are not blocked.
+pub struct DynamoUserDirectory {
+    pub table: String,
+    pub key_attribute: String,
+    pub blocked_attribute: String,
+    client: Client,
+}
+
+impl DynamoUserDirectory {
+    pub fn new(client: Client, table: String) -> Self {
+        Self { table, key_attribute: "principal_id".to_string(), blocked_attribute: "blocked".to_string(), client }
+    }
+
+    // Credentials and region of the Lambda, AWS_ENDPOINT_URL points to DynamoDB Local
+    pub async fn from_env(table: String) -> Self {
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .timeout_config(TimeoutConfig::builder().operation_timeout(DEFAULT_LOOKUP_TIMEOUT).build())
+            .load()
+            .await;
+        Self::new(Client::new(&config), table)
+    }
+
+    pub fn with_key_attribute(mut self, key_attribute: String) -> Self {
+        self.key_attribute = key_attribute;
+        self
+    }
+
+    pub fn with_blocked_attribute(mut self, blocked_attribute: String) -> Self {
+        self.blocked_attribute = blocked_attribute;
+        self
+    }
+
+    #[throws(anyhow::Error)]
+    async fn get_blocked(&self, principal: &str) -> bool {
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key(&self.key_attribute, AttributeValue::S(principal.to_string()))
+            .projection_expression("#blocked")
+            .expression_attribute_names("#blocked", &self.blocked_attribute)
+            .send()
+            .await
+            .map_err(|error| anyhow!("Could not read user {} from {}: {}", principal, self.table, DisplayErrorContext(&error)))?;
+        match output.item().and_then(|item| item.get(&self.blocked_attribute)) {
+            None => false,
+            Some(AttributeValue::Bool(blocked)) => *blocked,
+            Some(value) => Err(anyhow!("{} of user {} is not a boolean: {:?}", self.blocked_attribute, principal, value))?,
+        }
+    }
+}
+
+#[async_trait]
+impl UserDirectory for DynamoUserDirectory {
+    async fn is_blocked(&self, principal: &str) -> anyhow::Result<bool> {
+        let span = info_span!("lookup_user", table = %self.table, blocked = field::Empty, outcome = field::Empty, error = field::Empty);
+        let started = Instant::now();
+        let blocked = self.get_blocked(principal).instrument(span.clone()).await;
+        metrics::timing(metrics::USER_LOOKUP_TIME, started.elapsed());
+        if let Ok(blocked) = &blocked {
+            span.record("blocked", *blocked);
+        }
+        telemetry::record_outcome(&span, &blocked);
+        blocked
+    }
+}
+
+// Blocked principals kept in memory, for tests and local runs
+#[derive(Debug, Default)]
+pub struct InMemoryUserDirectory {
+    blocked: RwLock<HashSet<String>>,
+}
+
+impl InMemoryUserDirectory {
+    pub fn new(blocked: Vec<String>) -> Self {
+        Self { blocked: RwLock::new(blocked.into_iter().collect()) }
+    }
+
+    pub fn block(&self, principal: &str) {
+        self.blocked.write().unwrap().insert(principal.to_string());
+    }
+
+    pub fn unblock(&self, principal: &str) {
+        self.blocked.write().unwrap().remove(principal);
+    }
+}
+
+#[async_trait]
+impl UserDirectory for InMemoryUserDirectory {
+    async fn is_blocked(&self, principal: &str) -> anyhow::Result<bool> {
+        Ok(self.blocked.read().unwrap().contains(principal))
+    }
+}
+
+// Remembers the answers of another directory for ttl, per principal.
+// Failures are not remembered, the next invocation asks again.
+pub struct CachedUserDirectory<D: UserDirectory> {
+    pub ttl: Duration,
+    directory: D,
+    entries: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl<D: UserDirectory> CachedUserDirectory<D> {
+    pub fn new(directory: D, ttl: Duration) -> Self {
+        Self { ttl, directory, entries: Mutex::new(HashMap::new()) }
+    }
+
+    fn cached(&self, principal: &str) -> Option<bool> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(principal).filter(|(_, expires_at)| Instant::now() < *expires_at).map(|(blocked, _)| *blocked)
+    }
+}
+
+#[async_trait]
+impl<D: UserDirectory> UserDirectory for CachedUserDirectory<D> {
+    async fn is_blocked(&self, principal: &str) -> anyhow::Result<bool> {
+        if let Some(blocked) = self.cached(principal) {
+            debug!(target: "users.is_blocked", "Cache hit for {}", principal);
+            metrics::count(metrics::USER_CACHE_HIT);
+            return Ok(blocked);
+        }
+        metrics::count(metrics::USER_CACHE_MISS);
+        let blocked = self.directory.is_blocked(principal).await?;
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        // a warm container sees many principals, the expired ones go
+        entries.retain(|_, (_, expires_at)| now < *expires_at);
+        entries.insert(principal.to_string(), (blocked, now + self.ttl));
+        Ok(blocked)
+    }
+}
+
+// Consulted once the token is valid
+pub struct UserCheck {
+    pub on_error: DirectoryFailureMode,
+    directory: Box<dyn UserDirectory>,
+}
+
+impl UserCheck {
+    pub fn new(directory: Box<dyn UserDirectory>, on_error: DirectoryFailureMode) -> Self {
+        Self { on_error, directory }
+    }
+
+    // Blocked users are refused as blocked_user, an unavailable directory
+    // lets the user through or refuses the token as unavailable, depending on on_error
+    #[throws(anyhow::Error)]
+    pub async fn check(&self, principal: &str) {
+        match self.directory.is_blocked(principal).await {
+            Ok(false) => {},
+            Ok(true) => Err(AuthError::new(FailureCategory::BlockedUser, format!("User {} is blocked", principal)))?,
+            Err(error) => {
+                metrics::count(metrics::USER_LOOKUP_ERROR);
+                match self.on_error {
+                    DirectoryFailureMode::Open => warn!(target: "users.check", "User directory is unavailable, {} is let through: {}", principal, error),
+                    DirectoryFailureMode::Closed => Err(error.context(AuthError::new(FailureCategory::Unavailable, "User directory is unavailable")))?,
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::failures;
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    // counts the lookups, fails while unavailable
+    #[derive(Clone, Default)]
+    struct CountingDirectory {
+        lookups: Arc<AtomicUsize>,
+        unavailable: bool,
+    }
+
+    impl CountingDirectory {
+        fn unavailable() -> Self {
+            Self { unavailable: true, ..Self::default() }
+        }
+
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl UserDirectory for CountingDirectory {
+        async fn is_blocked(&self, principal: &str) -> anyhow::Result<bool> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            match self.unavailable {
+                true => Err(anyhow!("Connection refused")),
+                false => Ok(principal == "auth0|blocked"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_directory() {
+        let directory = InMemoryUserDirectory::new(vec!["auth0|blocked".to_string()]);
+        assert!(directory.is_blocked("auth0|blocked").await.unwrap());
+        assert!(!directory.is_blocked("auth0|123456").await.unwrap());
+        directory.block("auth0|123456");
+        directory.unblock("auth0|blocked");
+        assert!(directory.is_blocked("auth0|123456").await.unwrap());
+        assert!(!directory.is_blocked("auth0|blocked").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let directory = CountingDirectory::default();
+        let cached = CachedUserDirectory::new(directory.clone(), Duration::from_secs(60));
+        assert!(cached.is_blocked("auth0|blocked").await.unwrap());
+        assert!(cached.is_blocked("auth0|blocked").await.unwrap());
+        assert!(!cached.is_blocked("auth0|123456").await.unwrap());
+        assert_eq!(directory.lookups(), 2);
+        // expired entries are looked up again
+        let cached = CachedUserDirectory::new(directory.clone(), Duration::ZERO);
+        cached.is_blocked("auth0|blocked").await.unwrap();
+        cached.is_blocked("auth0|blocked").await.unwrap();
+        assert_eq!(directory.lookups(), 4);
+        // and failures are not remembered
+        let unavailable = CountingDirectory::unavailable();
+        let cached = CachedUserDirectory::new(unavailable.clone(), Duration::from_secs(60));
+        assert!(cached.is_blocked("auth0|123456").await.is_err());
+        assert!(cached.is_blocked("auth0|123456").await.is_err());
+        assert_eq!(unavailable.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let check = UserCheck::new(Box::new(InMemoryUserDirectory::new(vec!["auth0|blocked".to_string()])), DirectoryFailureMode::Closed);
+        check.check("auth0|123456").await.unwrap();
+        let error = check.check("auth0|blocked").await.unwrap_err();
+        assert_eq!(failures::classify(&error), FailureCategory::BlockedUser);
+        assert_eq!(error.to_string(), "User auth0|blocked is blocked");
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_directory() {
+        let closed = UserCheck::new(Box::new(CountingDirectory::unavailable()), DirectoryFailureMode::Closed);
+        let error = closed.check("auth0|123456").await.unwrap_err();
+        assert_eq!(failures::classify(&error), FailureCategory::Unavailable);
+        assert_eq!(error.root_cause().to_string(), "Connection refused");
+        let open = UserCheck::new(Box::new(CountingDirectory::unavailable()), DirectoryFailureMode::Open);
+        open.check("auth0|123456").await.unwrap();
+        // a blocked user is refused either way
+        let blocking = UserCheck::new(Box::new(InMemoryUserDirectory::new(vec!["auth0|blocked".to_string()])), DirectoryFailureMode::Open);
+        assert!(blocking.check("auth0|blocked").await.is_err());
+    }
+}
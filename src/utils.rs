@@ -1,32 +1,220 @@
-use std::collections::HashMap;
+use std::str::FromStr;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use fehler::throws;
+use jsonwebtoken::{errors::ErrorKind, Algorithm};
+use log::warn;
+use tracing::{field, info_span, Instrument};
+use serde_json::Value;
 
-use crate::structs::JWK;
+use crate::{enums::FailureCategory, structs::{AuthError, KeySet, OpenIdConfiguration, RejectedKey, JWK}, telemetry};
 
 #[throws(anyhow::Error)]
 pub async fn get_jwks(url: String) -> Vec<JWK> {
+    let span = info_span!("get_jwks", url = %url, keys = field::Empty, outcome = field::Empty, error = field::Empty);
+    let key_set = get_key_set(url).instrument(span.clone()).await;
+    if let Ok(key_set) = &key_set {
+        span.record("keys", key_set.accepted.len() as u64);
+    }
+    telemetry::record_outcome(&span, &key_set);
+    key_set?.accepted
+}
+
+#[throws(anyhow::Error)]
+pub async fn get_key_set(url: String) -> KeySet {
     let response = reqwest::get(url)
     .await?
-    .json::<HashMap<String, Vec<JWK>>>()
+    .json::<Value>()
+    .await?;
+    parse_key_set(response)?
+}
+
+// OpenID Connect Discovery 1.0, section 4
+pub fn openid_configuration_url(issuer: &str) -> String {
+    format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'))
+}
+
+#[throws(anyhow::Error)]
+pub async fn get_openid_configuration(issuer: &str) -> OpenIdConfiguration {
+    let configuration = reqwest::get(openid_configuration_url(issuer))
+    .await?
+    .error_for_status()?
+    .json::<OpenIdConfiguration>()
     .await?;
-    if let Some(keys) = response.get("keys") {
-        keys.clone()
-    } else {
-        bail!("No keys found in request to jwks endpoint")
+    // the issuer must match exactly, otherwise a compromised or misconfigured
+    // endpoint could hand out keys for somebody else's tokens
+    if configuration.issuer != issuer {
+        bail!("Issuer {} in the openid configuration does not match {}", configuration.issuer, issuer)
+    }
+    configuration
+}
+
+// A key we cannot use (unknown kty, encryption key, malformed components...)
+// is skipped instead of failing the whole set, so a provider publishing a new
+// kind of key does not lock everybody out.
+#[throws(anyhow::Error)]
+pub fn parse_key_set(response: Value) -> KeySet {
+    let keys = match response.get("keys").and_then(Value::as_array) {
+        Some(keys) => keys,
+        None => bail!("No keys found in request to jwks endpoint"),
+    };
+    let mut key_set = KeySet::default();
+    for key in keys {
+        let usable = serde_json::from_value::<JWK>(key.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|jwk| jwk.check_signature_use().map(|_| jwk));
+        match usable {
+            Ok(jwk) => key_set.accepted.push(jwk),
+            Err(error) => {
+                let rejected = RejectedKey {
+                    kid: key.get("kid").and_then(Value::as_str).map(String::from),
+                    kty: key.get("kty").and_then(Value::as_str).map(String::from),
+                    reason: error.to_string(),
+                };
+                warn!(target: "utils.parse_key_set", "Skipping key {:?} ({:?}): {}", rejected.kid, rejected.kty, rejected.reason);
+                key_set.rejected.push(rejected);
+            }
+        }
+    }
+    key_set
+}
+
+// Reads iss from the payload WITHOUT verifying the signature. Only meant to
+// pick the issuer profile the token is then verified with.
+#[throws(anyhow::Error)]
+pub fn unverified_issuer(token: &str) -> String {
+    let payload = match token.split('.').nth(1) {
+        Some(payload) => payload,
+        None => Err(jsonwebtoken::errors::Error::from(ErrorKind::InvalidToken))?,
+    };
+    let claims: Value = serde_json::from_slice(&base64::decode_config(payload, base64::URL_SAFE_NO_PAD)?)?;
+    match claims.get("iss").and_then(Value::as_str) {
+        Some(issuer) => issuer.to_string(),
+        None => bail!("Token has no iss claim"),
     }
 }
 
 #[throws(anyhow::Error)]
 pub fn find_jwk(kid: String, keys: Vec<JWK>) -> JWK {
-    let mut iter = keys.iter();
-    let header = iter.find(|&x| x.kid == Some(kid.clone()));
-    if let Some(found) = header {
-        found.clone()
-    } else {
-        bail!("No key corresponding to kid {} found in the jkws", kid)
+    let span = info_span!("find_jwk", kid = %kid, keys = keys.len() as u64, outcome = field::Empty, error = field::Empty);
+    let _entered = span.enter();
+    let jwk = match keys.into_iter().find(|key| key.kid.as_deref() == Some(kid.as_str())) {
+        Some(jwk) => Ok(jwk),
+        None => Err(anyhow!("No key corresponding to kid {} found in the jkws", kid)),
+    };
+    telemetry::record_outcome(&span, &jwk);
+    jwk?
+}
+
+// The token of an Authorization header, with or without the Bearer scheme
+#[throws(anyhow::Error)]
+pub fn bearer_token(authorization: &str) -> String {
+    let authorization = authorization.trim();
+    let token = match authorization.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        None if authorization.eq_ignore_ascii_case("bearer") => "",
+        _ => authorization,
+    };
+    if token.is_empty() {
+        Err(AuthError::new(FailureCategory::MissingToken, "No token in the request"))?
+    }
+    token.to_string()
+}
+
+// Parses a comma separated allow-list such as "RS256,ES256,EdDSA"
+#[throws(anyhow::Error)]
+pub fn parse_algorithms(algorithms: &str) -> Vec<Algorithm> {
+    let mut parsed = vec![];
+    for name in algorithms.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match Algorithm::from_str(name) {
+            Ok(Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) => bail!("Symmetric algorithm {} cannot be used with a jwks", name),
+            Ok(algorithm) => parsed.push(algorithm),
+            Err(_) => bail!("Unknown algorithm {}", name),
+        }
+    }
+    if parsed.is_empty() {
+        bail!("No algorithms allowed")
+    }
+    parsed
+}
+
+// Resource paths end up in an execute-api ARN, where only * works as wildcard
+#[throws(anyhow::Error)]
+pub fn validate_resource_path(path: &str) {
+    if path.trim_start_matches('/').split('/').skip(1).any(str::is_empty) {
+        bail!("Resource path {:?} has an empty segment", path)
+    }
+    if let Some(invalid) = path.chars().find(|c| !(c.is_ascii_alphanumeric() || "/-._~*:@!$&'()+,;=%".contains(*c))) {
+        bail!("Resource path {:?} contains {:?}", path, invalid)
     }
 }
 
-    
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_key_set_skips_unusable_keys() {
+        let jwks: Value = serde_json::from_str(&std::fs::read_to_string("tests/resources/auth0.keys.json").unwrap()).unwrap();
+        let mut keys = jwks["keys"].as_array().unwrap().clone();
+        keys.push(serde_json::json!({ "kty": "oct", "kid": "symmetric", "k": "c2VjcmV0" }));
+        keys.push(serde_json::json!({ "kty": "RSA", "kid": "encryption", "use": "enc", "alg": "RSA-OAEP", "n": "AQAB", "e": "AQAB" }));
+        keys.push(serde_json::json!({ "kty": "RSA", "kid": "wrapping", "key_ops": ["wrapKey"], "n": "AQAB", "e": "AQAB" }));
+        keys.push(serde_json::json!({ "kty": "RSA", "kid": "incomplete", "use": "sig" }));
+        keys.push(serde_json::json!({ "kty": "EC", "kid": "verify", "key_ops": ["verify"], "crv": "P-256",
+            "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU", "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0" }));
+        let key_set = parse_key_set(serde_json::json!({ "keys": keys })).unwrap();
+        let accepted: Vec<_> = key_set.accepted.iter().map(|key| key.kid.clone().unwrap()).collect();
+        assert_eq!(accepted, vec!["HbWL3SP7IJzrl6zlhuU4j", "lOeEb1RLTmqh7H_8ThNzT", "verify"]);
+        assert!(key_set.accepted[0].x5c.is_some() && key_set.accepted[0].x5t.is_some());
+        let rejected: Vec<_> = key_set.rejected.iter().map(|key| key.kid.clone().unwrap()).collect();
+        assert_eq!(rejected, vec!["symmetric", "encryption", "wrapping", "incomplete"]);
+        assert!(key_set.rejected[0].reason.contains("oct"));
+        assert_eq!(key_set.rejected[2].reason, "Key operations [\"wrapKey\"] do not include verify");
+    }
+
+    #[test]
+    fn test_parse_key_set_without_keys() {
+        assert!(parse_key_set(serde_json::json!({ "error": "not found" })).is_err());
+    }
+
+    #[test]
+    fn test_unverified_issuer() {
+        let payload = base64::encode_config(r#"{"iss":"https://issuer.example.com/","sub":"user"}"#, base64::URL_SAFE_NO_PAD);
+        assert_eq!(unverified_issuer(&format!("e30.{}.c2ln", payload)).unwrap(), "https://issuer.example.com/");
+        let payload = base64::encode_config(r#"{"sub":"user"}"#, base64::URL_SAFE_NO_PAD);
+        assert!(unverified_issuer(&format!("e30.{}.c2ln", payload)).is_err());
+        assert!(unverified_issuer("not a token").is_err());
+    }
+
+    #[test]
+    fn test_validate_resource_path() {
+        for path in ["", "*", "/botos", "botos/*", "/botos/42/settings", "/users/auth0%7C123"] {
+            assert!(validate_resource_path(path).is_ok(), "{}", path);
+        }
+        for path in ["/botos//settings", "/botos/", "/users/{user_id}", "/botos?page=2", "/bo tos"] {
+            assert!(validate_resource_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer e30.e30.c2ln").unwrap(), "e30.e30.c2ln");
+        assert_eq!(bearer_token("bearer  e30.e30.c2ln ").unwrap(), "e30.e30.c2ln");
+        assert_eq!(bearer_token("e30.e30.c2ln").unwrap(), "e30.e30.c2ln");
+        for authorization in ["", "  ", "Bearer", "Bearer  "] {
+            let error = bearer_token(authorization).unwrap_err();
+            assert_eq!(error.downcast_ref::<AuthError>().unwrap().category, FailureCategory::MissingToken);
+        }
+    }
+
+    #[test]
+    fn test_parse_algorithms() {
+        let algorithms = parse_algorithms("RS256, ES256,EdDSA").unwrap();
+        assert_eq!(algorithms, vec![Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA]);
+        assert!(parse_algorithms("RS256,HS256").is_err());
+        assert!(parse_algorithms("none").is_err());
+        assert!(parse_algorithms("").is_err());
+    }
+}
@@ -0,0 +1,165 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use jwt_authorizer::{enums::{AudienceMatch, StringOrArray}, structs::{IssuerConfig, TimeValidation}};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+// The signing keys and claims of the unit tests
+#[path = "../../src/test_support/keys.rs"]
+mod keys;
+
+pub use keys::*;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(body: &str) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+// Minimal HTTP/1.1 stand-in for the identity provider and other upstreams,
+// counting how many requests it has served.
+pub struct TestServer {
+    pub url: String,
+    hits: Arc<AtomicUsize>,
+}
+
+impl TestServer {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let handler = Arc::new(handler);
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                };
+                let handler = handler.clone();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let request = match read_request(&mut socket).await {
+                        Some(request) => request,
+                        None => return,
+                    };
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let response = handler(&request);
+                    let mut raw = format!("HTTP/1.1 {} OK\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+                    for (name, value) in &response.headers {
+                        raw.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    raw.push_str("\r\n");
+                    raw.push_str(&response.body);
+                    let _ = socket.write_all(raw.as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        Self { url, hits }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let content_length: usize = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let body = String::from_utf8_lossy(&buffer[header_end..]).to_string();
+    Some(Request { method, path, headers, body })
+}
+
+pub fn read_resource(name: &str) -> String {
+    std::fs::read_to_string(format!("{}/tests/resources/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+// An issuer whose keys are at keys_repo, with the defaults of everything else
+pub fn issuer_config(issuer: &str, audience: &str, keys_repo: String) -> IssuerConfig {
+    IssuerConfig {
+        issuer: issuer.to_string(),
+        audience: StringOrArray::Str(audience.to_string()),
+        audience_match: AudienceMatch::Any,
+        keys_repo: Some(keys_repo),
+        algorithms: None,
+        x5c_trust_anchors: None,
+        required_claims: vec![],
+        claims_namespace: None,
+        principal_claim: "sub".to_string(),
+        time_validation: TimeValidation::default(),
+    }
+}
+
+// Serves the given key set at /jwks
+pub async fn jwks_server(keys: &[&TestKey]) -> TestServer {
+    let body = jwks(keys);
+    TestServer::start(move |_| Response::json(&body)).await
+}
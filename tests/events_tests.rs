@@ -0,0 +1,125 @@
+mod common;
+
+#[cfg(test)]
+mod events_tests {
+    use jwt_authorizer::{
+        enums::{AuthorizerEvent, AuthorizerResponse, FailureCategory, HttpMethod, TokenSource},
+        failures,
+        structs::{AuthError, HttpApiSimpleResponse, RequestValues},
+    };
+    use serde_json::{json, Value};
+
+    use crate::common::read_resource;
+
+    fn request_event() -> Value {
+        serde_json::from_str(&read_resource("request_authorizer_event.json")).unwrap()
+    }
+
+    fn http_api_event() -> Value {
+        serde_json::from_str(&read_resource("http_api_authorizer_event.json")).unwrap()
+    }
+
+    fn sources(sources: &str) -> Vec<TokenSource> {
+        sources.split(',').map(|source| source.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_token_event() {
+        let event = AuthorizerEvent::from_value(json!({
+            "type": "TOKEN",
+            "authorizationToken": "Bearer header.token.value",
+            "methodArn": "arn:aws:execute-api:eu-west-1:481724841148:5q06q4o1qe/prod/GET/boto",
+        })).unwrap();
+        assert!(matches!(event, AuthorizerEvent::Token(_)));
+        assert_eq!(event.method_arn(), "arn:aws:execute-api:eu-west-1:481724841148:5q06q4o1qe/prod/GET/boto");
+        // the sources only apply to REQUEST authorizers
+        assert_eq!(event.token(&sources("query:access_token")).unwrap(), "header.token.value");
+        assert!(event.route().is_none());
+    }
+
+    #[test]
+    fn test_request_event() {
+        let event = AuthorizerEvent::from_value(request_event()).unwrap();
+        let request = match &event {
+            AuthorizerEvent::Request(request) => request,
+            _ => panic!("expected a REQUEST event"),
+        };
+        assert_eq!(request.header("Host"), Some("5q06q4o1qe.execute-api.eu-west-1.amazonaws.com"));
+        assert_eq!(request.header("x-forwarded-token"), Some("forwarded.token.value"));
+        assert_eq!(request.query_parameter("tag"), Some("a"));
+        assert_eq!(request.cookie("theme"), Some("dark"));
+        assert_eq!(request.path_parameters.as_ref().unwrap()["botoId"], "sdfdsfsdfs");
+        assert_eq!(request.stage_variables.as_ref().unwrap()["environment"], "prod");
+        assert_eq!(request.request_context.as_ref().unwrap().resource_path.as_deref(), Some("/boto/{botoId}"));
+        assert_eq!(event.route(), Some((HttpMethod::GET, "/boto/sdfdsfsdfs")));
+    }
+
+    #[test]
+    fn test_token_sources() {
+        let event = AuthorizerEvent::from_value(request_event()).unwrap();
+        assert_eq!(event.token(&sources("header:Authorization")).unwrap(), "header.token.value");
+        assert_eq!(event.token(&sources("cookie:access_token,header:Authorization")).unwrap(), "cookie.token.value");
+        assert_eq!(event.token(&sources("query:access_token")).unwrap(), "query.token.value");
+        // the first source holding a token wins
+        assert_eq!(event.token(&sources("header:X-Missing,cookie:missing,query:access_token")).unwrap(), "query.token.value");
+        let error = event.token(&sources("header:X-Missing")).unwrap_err();
+        assert_eq!(error.downcast_ref::<AuthError>().unwrap().category, FailureCategory::MissingToken);
+    }
+
+    #[test]
+    fn test_request_event_with_null_fields() {
+        let mut event: Value = serde_json::from_str(&read_resource("example_event_from_api_gateway.json")).unwrap();
+        event["type"] = json!("REQUEST");
+        event["methodArn"] = json!("arn:aws:execute-api:eu-west-1:481724841148:5q06q4o1qe/test-invoke-stage/GET/boto/sdfdsfsdfs");
+        let event = AuthorizerEvent::from_value(event).unwrap();
+        assert_eq!(event.route(), Some((HttpMethod::GET, "/boto/sdfdsfsdfs")));
+        assert!(event.token(&sources("header:Authorization")).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_events() {
+        assert!(AuthorizerEvent::from_value(json!({ "type": "COGNITO" })).is_err());
+        assert!(AuthorizerEvent::from_value(json!({ "methodArn": "arn" })).is_err());
+        assert!("body:token".parse::<TokenSource>().is_err());
+        assert!("header:".parse::<TokenSource>().is_err());
+    }
+
+    #[test]
+    fn test_http_api_event() {
+        let event = AuthorizerEvent::from_value(http_api_event()).unwrap();
+        let request = match &event {
+            AuthorizerEvent::HttpApi(request) => request,
+            _ => panic!("expected an HTTP API event"),
+        };
+        assert_eq!(event.method_arn(), "arn:aws:execute-api:eu-west-1:481724841148:7n3h2d9k1x/prod/GET/boto/sdfdsfsdfs");
+        assert_eq!(request.header("Host"), Some("7n3h2d9k1x.execute-api.eu-west-1.amazonaws.com"));
+        assert_eq!(request.query_parameter("tag"), Some("a"));
+        assert_eq!(request.cookie("theme"), Some("dark"));
+        assert_eq!(event.route(), Some((HttpMethod::GET, "/boto/sdfdsfsdfs")));
+    }
+
+    #[test]
+    fn test_http_api_token_sources() {
+        let event = AuthorizerEvent::from_value(http_api_event()).unwrap();
+        assert_eq!(event.token(&sources("header:Authorization")).unwrap(), "header.token.value");
+        assert_eq!(event.token(&sources("cookie:access_token")).unwrap(), "cookie.token.value");
+        assert_eq!(event.token(&sources("query:access_token")).unwrap(), "query.token.value");
+        // the identity source is the last resort
+        assert_eq!(event.token(&sources("header:X-Missing")).unwrap(), "identity.token.value");
+        let mut without_identity = http_api_event();
+        without_identity["identitySource"] = Value::Null;
+        let event = AuthorizerEvent::from_value(without_identity).unwrap();
+        let error = event.token(&sources("header:X-Missing")).unwrap_err();
+        assert_eq!(error.downcast_ref::<AuthError>().unwrap().category, FailureCategory::MissingToken);
+    }
+
+    #[test]
+    fn test_http_api_simple_response() {
+        let allowed = AuthorizerResponse::Simple(HttpApiSimpleResponse { is_authorized: true, context: json!({ "sub": "auth0|123456" }) });
+        assert_eq!(serde_json::to_value(&allowed).unwrap(), json!({ "isAuthorized": true, "context": { "sub": "auth0|123456" } }));
+        let error = AuthError::new(FailureCategory::Expired, "Token has expired").into();
+        let refused = serde_json::to_value(AuthorizerResponse::Simple(failures::simple_response(&error))).unwrap();
+        assert_eq!(refused["isAuthorized"], false);
+        assert_eq!(refused["context"]["failureCategory"], "expired");
+    }
+}
@@ -1,6 +1,13 @@
+mod common;
+
 #[cfg(test)]
 mod integration_tests {
-    use jwt_authorizer::{auth::Auth, utils};
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+    use jsonwebtoken::Algorithm;
+    use jwt_authorizer::{auth::Auth, cache::JwksCache, enums::FailureCategory, failures, metrics, utils};
+
+    use crate::common::{read_resource, Response, TestServer};
 
     #[tokio::test]
     async fn test_read_keys_from_auth0_ok()  {
@@ -11,7 +18,7 @@ mod integration_tests {
     #[tokio::test]
     async fn test_read_keys_from_auth0_bad_url()  {
         let keys = utils::get_jwks("https://xxx.eu.aaa.com/.well-known/jwks.json".to_string()).await;
-        assert_eq!(keys.is_err(), true);
+        assert!(keys.is_err());
     }
 
     #[tokio::test]
@@ -20,10 +27,211 @@ mod integration_tests {
         let issuer = "https://boto.eu.auth0.com/".to_string();
         let keys = utils::get_jwks("https://boto.eu.auth0.com/.well-known/jwks.json".to_string()).await;
         let auth = Auth::new(
-            audience.clone(), issuer.clone(), keys.unwrap()
+            vec![audience.clone()], issuer.clone(), keys.unwrap()
         );
         assert_eq!(auth.issuer, issuer);
-        assert_eq!(auth.audience, audience);
+        assert_eq!(auth.audiences, vec![audience]);
+        assert_eq!(auth.keys.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_reuses_keys()  {
+        let server = TestServer::start(|_| Response::json(&read_resource("auth0.keys.json"))).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60));
+        assert_eq!(cache.get_keys().await.unwrap().len(), 2);
+        assert_eq!(cache.get_keys().await.unwrap().len(), 2);
+        assert_eq!(server.hits(), 1);
+        cache.invalidate().await;
+        cache.get_keys().await.unwrap();
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_honours_cache_control()  {
+        let server = TestServer::start(|_| {
+            Response::json(&read_resource("auth0.keys.json")).with_header("Cache-Control", "max-age=0")
+        }).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60));
+        cache.get_keys().await.unwrap();
+        cache.get_keys().await.unwrap();
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_falls_back_to_ttl()  {
+        let server = TestServer::start(|_| Response::json(&read_resource("auth0.keys.json"))).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_millis(50));
+        cache.get_keys().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cache.get_keys().await.unwrap();
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_concurrent_invocations()  {
+        let server = TestServer::start(|_| Response::json(&read_resource("auth0.keys.json"))).await;
+        let cache = Arc::new(JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60)));
+        let lookups: Vec<_> = (0..10).map(|_| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get_keys().await.unwrap().len() })
+        }).collect();
+        for lookup in lookups {
+            assert_eq!(lookup.await.unwrap(), 2);
+        }
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_metrics()  {
+        let server = TestServer::start(|_| Response::json(&read_resource("auth0.keys.json"))).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60));
+        let ((), recorder) = metrics::scope(async {
+            cache.get_keys().await.unwrap();
+            cache.get_keys().await.unwrap();
+        }).await;
+        let config = metrics::MetricsConfig::new("JwtAuthorizer".to_string());
+        let document = config.document(&recorder, std::time::SystemTime::now()).unwrap();
+        assert_eq!(document[metrics::JWKS_CACHE_MISS], 1.0);
+        assert_eq!(document[metrics::JWKS_CACHE_HIT], 1.0);
+        assert_eq!(document[metrics::JWKS_FETCH], 1.0);
+        assert!(document[metrics::JWKS_FETCH_TIME].is_number());
+        assert!(document.get(metrics::JWKS_FETCH_ERROR).is_none());
+        let failing = TestServer::start(|_| Response::json("{}").with_status(500)).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", failing.url), Duration::from_secs(60));
+        let (_, recorder) = metrics::scope(cache.get_keys()).await;
+        assert_eq!(recorder.value(metrics::JWKS_FETCH_ERROR), Some(1.0.into()));
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_does_not_cache_errors()  {
+        let server = TestServer::start(|_| Response::json("{}").with_status(500)).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60));
+        assert!(cache.get_keys().await.is_err());
+        // an outage of the IdP, not a bad token
+        let error = cache.get_keys().await.unwrap_err();
+        assert_eq!(failures::classify(&error), FailureCategory::Unavailable);
+        assert_eq!(server.hits(), 2);
+    }
+
+    // serves the original key set first and a rotated one afterwards
+    async fn rotating_jwks_server() -> TestServer {
+        let served = AtomicUsize::new(0);
+        TestServer::start(move |_| {
+            let keys = read_resource("auth0.keys.json");
+            if served.fetch_add(1, Ordering::SeqCst) == 0 {
+                Response::json(&keys)
+            } else {
+                Response::json(&keys.replace("lOeEb1RLTmqh7H_8ThNzT", "rotated-kid"))
+            }
+        }).await
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_refreshes_on_unknown_kid()  {
+        let server = rotating_jwks_server().await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60))
+            .with_min_refresh_interval(Duration::from_secs(0));
+        cache.get_keys().await.unwrap();
+        let keys = cache.get_keys_for_kid("rotated-kid").await.unwrap();
+        assert!(utils::find_jwk("rotated-kid".to_string(), keys).is_ok());
+        assert_eq!(server.hits(), 2);
+        // known kids are served from the cache
+        cache.get_keys_for_kid("rotated-kid").await.unwrap();
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_rate_limits_unknown_kid_refreshes()  {
+        let server = TestServer::start(|_| Response::json(&read_resource("auth0.keys.json"))).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60))
+            .with_min_refresh_interval(Duration::from_secs(60));
+        for attempt in 0..5 {
+            let keys = cache.get_keys_for_kid(&format!("random-kid-{}", attempt)).await.unwrap();
+            assert_eq!(keys.len(), 2);
+        }
+        // initial download plus a single forced refresh
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_coalesces_unknown_kid_refreshes()  {
+        let server = rotating_jwks_server().await;
+        let cache = Arc::new(JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60))
+            .with_min_refresh_interval(Duration::from_secs(0)));
+        cache.get_keys().await.unwrap();
+        let lookups: Vec<_> = (0..10).map(|_| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get_keys_for_kid("rotated-kid").await.unwrap() })
+        }).collect();
+        for lookup in lookups {
+            assert!(utils::find_jwk("rotated-kid".to_string(), lookup.await.unwrap()).is_ok());
+        }
+        assert_eq!(server.hits(), 2);
+    }
+
+    fn openid_configuration(issuer: &str, jwks_uri: &str) -> String {
+        serde_json::json!({
+            "issuer": issuer,
+            "jwks_uri": jwks_uri,
+            "id_token_signing_alg_values_supported": ["HS256", "RS256", "PS256", "none"],
+            "token_endpoint": format!("{}oauth/token", issuer)
+        }).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_auth_from_issuer()  {
+        let server = TestServer::start(|request| {
+            let base = format!("http://{}", request.headers["host"]);
+            match request.path.as_str() {
+                "/.well-known/openid-configuration" => Response::json(&openid_configuration(&format!("{}/", base), &format!("{}/jwks", base))),
+                "/jwks" => Response::json(&read_resource("auth0.keys.json")),
+                _ => Response::json("{}").with_status(404),
+            }
+        }).await;
+        let issuer = format!("{}/", server.url);
+        let auth = Auth::from_issuer(vec!["audience".to_string()], issuer.clone()).await.unwrap();
+        assert_eq!(auth.issuer, issuer);
         assert_eq!(auth.keys.len(), 2);
+        assert_eq!(auth.algorithms, vec![Algorithm::RS256, Algorithm::PS256]);
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_auth_from_issuer_mismatch()  {
+        let server = TestServer::start(|_| {
+            Response::json(&openid_configuration("https://attacker.example.com/", "https://attacker.example.com/jwks"))
+        }).await;
+        let auth = Auth::from_issuer(vec!["audience".to_string()], format!("{}/", server.url)).await;
+        assert!(auth.is_err());
+        // the keys of a mismatching issuer are never downloaded
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_openid_configuration_without_supported_algorithms()  {
+        let server = TestServer::start(|request| {
+            let issuer = format!("http://{}", request.headers["host"]);
+            Response::json(&serde_json::json!({
+                "issuer": issuer,
+                "jwks_uri": format!("{}/jwks", issuer),
+                "id_token_signing_alg_values_supported": ["HS256"]
+            }).to_string())
+        }).await;
+        let configuration = utils::get_openid_configuration(&server.url).await.unwrap();
+        assert!(configuration.signing_algorithms().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cache_reports_rejected_keys()  {
+        let mut jwks: serde_json::Value = serde_json::from_str(&read_resource("auth0.keys.json")).unwrap();
+        jwks["keys"][1]["kty"] = "oct".into();
+        let server = TestServer::start(move |_| Response::json(&jwks.to_string())).await;
+        let cache = JwksCache::new(format!("{}/.well-known/jwks.json", server.url), Duration::from_secs(60));
+        let key_set = cache.get_key_set().await.unwrap();
+        assert_eq!(key_set.accepted.len(), 1);
+        assert_eq!(key_set.rejected.len(), 1);
+        assert_eq!(key_set.rejected[0].kid.as_deref(), Some("lOeEb1RLTmqh7H_8ThNzT"));
+        assert_eq!(cache.get_keys().await.unwrap().len(), 1);
+        assert_eq!(server.hits(), 1);
     }
 }
\ No newline at end of file
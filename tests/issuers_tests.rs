@@ -0,0 +1,59 @@
+mod common;
+
+#[cfg(test)]
+mod issuers_tests {
+    use std::time::Duration;
+
+    use jsonwebtoken::Algorithm;
+    use jwt_authorizer::issuers::{IssuerProfile, IssuerRegistry};
+
+    use crate::common::{claims, issuer_config, jwks_server, TestKey};
+
+    const AUTH0: &str = "https://boto.eu.auth0.com/";
+    const MACHINES: &str = "https://m2m.boto.internal/";
+
+    #[tokio::test]
+    async fn test_tokens_from_multiple_issuers()  {
+        let (auth0_key, machines_key) = (TestKey::rsa("auth0", Algorithm::RS256), TestKey::rsa("m2m", Algorithm::RS256));
+        let (auth0_server, machines_server) = (jwks_server(&[&auth0_key]).await, jwks_server(&[&machines_key]).await);
+        let registry = IssuerRegistry::from_configs(&[
+            issuer_config(AUTH0, "https://api.boto.io", format!("{}/jwks", auth0_server.url)),
+            issuer_config(MACHINES, "internal", format!("{}/jwks", machines_server.url)),
+        ], Duration::from_secs(60), Duration::from_secs(60)).await.unwrap();
+        let token = auth0_key.sign(&claims(AUTH0, "https://api.boto.io"));
+        assert!(registry.auth_for_token(&token).await.unwrap().validate_token(&token).is_ok());
+        let token = machines_key.sign(&claims(MACHINES, "internal"));
+        assert!(registry.auth_for_token(&token).await.unwrap().validate_token(&token).is_ok());
+        // audiences are per issuer
+        let token = machines_key.sign(&claims(MACHINES, "https://api.boto.io"));
+        assert!(registry.auth_for_token(&token).await.unwrap().validate_token(&token).is_err());
+        assert_eq!((auth0_server.hits(), machines_server.hits()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_issuer_claim_does_not_bypass_signature()  {
+        let (auth0_key, machines_key) = (TestKey::rsa("auth0", Algorithm::RS256), TestKey::rsa("m2m", Algorithm::RS256));
+        let (auth0_server, machines_server) = (jwks_server(&[&auth0_key]).await, jwks_server(&[&machines_key]).await);
+        let mut registry = IssuerRegistry::new();
+        registry.register(IssuerProfile::from_config(&issuer_config(AUTH0, "api", format!("{}/jwks", auth0_server.url)), Duration::from_secs(60), Duration::from_secs(60)).await.unwrap());
+        registry.register(IssuerProfile::from_config(&issuer_config(MACHINES, "api", format!("{}/jwks", machines_server.url)), Duration::from_secs(60), Duration::from_secs(60)).await.unwrap());
+        // claims to come from auth0 but is signed with the machine-to-machine key
+        let token = machines_key.sign(&claims(AUTH0, "api"));
+        let result = registry.auth_for_token(&token).await.unwrap().validate_token(&token);
+        assert!(result.err().unwrap().to_string().contains("No key corresponding to kid m2m"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_issuer()  {
+        let key = TestKey::rsa("auth0", Algorithm::RS256);
+        let server = jwks_server(&[&key]).await;
+        let registry = IssuerRegistry::from_configs(&[issuer_config(AUTH0, "api", format!("{}/jwks", server.url))], Duration::from_secs(60), Duration::from_secs(60)).await.unwrap();
+        let token = key.sign(&claims("https://evil.example.com/", "api"));
+        let error = registry.auth_for_token(&token).await.err().unwrap();
+        assert_eq!(error.to_string(), "Unknown issuer https://evil.example.com/");
+        assert!(registry.contains(AUTH0));
+        assert!(!registry.contains("https://evil.example.com/"));
+        // no keys are downloaded for unknown issuers
+        assert_eq!(server.hits(), 0);
+    }
+}
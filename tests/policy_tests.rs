@@ -0,0 +1,77 @@
+mod common;
+
+#[cfg(test)]
+mod policy_tests {
+    use std::collections::BTreeMap;
+
+    use jwt_authorizer::{arn::MethodArn, enums::{Effect, HttpMethod}, structs::{APIGatewayPolicyBuilder, IAMPolicyCondition}};
+    use serde_json::{json, Value};
+
+    use crate::common::read_resource;
+
+    fn builder() -> APIGatewayPolicyBuilder {
+        APIGatewayPolicyBuilder::new("eu-west-1", "123456789012", "abcdef123", "prod")
+    }
+
+    fn source_ip(cidr: &str) -> IAMPolicyCondition {
+        BTreeMap::from([("IpAddress".to_string(), BTreeMap::from([("aws:SourceIp".to_string(), json!([cidr]))]))])
+    }
+
+    #[test]
+    fn test_policy_matches_golden_file() {
+        let policy = builder()
+            .deny_method(HttpMethod::DELETE, "/users/*".to_string())
+            .allow_method(HttpMethod::POST, "/botos".to_string())
+            .allow_method_with_conditions(HttpMethod::ALL, "/admin/*".to_string(), source_ip("203.0.113.0/24"))
+            .allow_method(HttpMethod::GET, "/botos/*".to_string())
+            .deny_method(HttpMethod::ALL, "/internal/*".to_string())
+            .allow_method(HttpMethod::GET, "/botos/*".to_string())
+            .build()
+            .unwrap();
+        let golden: Value = serde_json::from_str(&read_resource("policy.json")).unwrap();
+        assert_eq!(serde_json::to_value(&policy).unwrap(), golden);
+    }
+
+    #[test]
+    fn test_output_does_not_depend_on_insertion_order() {
+        let calls: Vec<(Effect, HttpMethod, &str)> = vec![
+            (Effect::Deny, HttpMethod::PUT, "/botos/*"),
+            (Effect::Allow, HttpMethod::GET, "/users/*"),
+            (Effect::Allow, HttpMethod::GET, "/botos/*"),
+        ];
+        let forward = calls.iter().fold(builder(), |builder, (effect, method, path)| builder.add_method(*effect, *method, *path));
+        let backward = calls.iter().rev().fold(builder(), |builder, (effect, method, path)| builder.add_method(*effect, *method, *path));
+        let (forward, backward) = (forward.build().unwrap(), backward.build().unwrap());
+        assert_eq!(serde_json::to_string(&forward).unwrap(), serde_json::to_string(&backward).unwrap());
+        assert_eq!(forward.Statement.len(), 2);
+    }
+
+    #[test]
+    fn test_all_methods() {
+        let policy = builder().deny_all_methods().build().unwrap();
+        assert_eq!(policy.Statement[0].Effect, Effect::Deny);
+        assert_eq!(policy.Statement[0].Resource, vec!["arn:aws:execute-api:eu-west-1:123456789012:abcdef123/prod/*/*"]);
+        assert!(policy.Statement[0].Condition.is_none());
+    }
+
+    #[test]
+    fn test_invalid_resource_paths() {
+        let error = builder()
+            .allow_method(HttpMethod::GET, "/botos/{boto_id}".to_string())
+            .deny_method(HttpMethod::GET, "/users//settings".to_string())
+            .build()
+            .unwrap_err();
+        assert!(error.to_string().contains("/botos/{boto_id}"));
+        assert!(error.to_string().contains("users//settings"));
+    }
+
+    #[test]
+    fn test_builder_from_method_arn() {
+        let arn: MethodArn = "arn:aws-us-gov:execute-api:us-gov-west-1:123456789012:abcdef123/prod/GET/botos/42".parse().unwrap();
+        let policy = APIGatewayPolicyBuilder::from_method_arn(&arn)
+            .allow_method(HttpMethod::GET, "/botos/*".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(policy.Statement[0].Resource, vec!["arn:aws-us-gov:execute-api:us-gov-west-1:123456789012:abcdef123/prod/GET/botos/*"]);
+    }
+}
@@ -0,0 +1,66 @@
+mod common;
+
+#[cfg(test)]
+mod redaction_tests {
+    use std::{sync::{Mutex, Once}, time::Duration};
+
+    use jsonwebtoken::Algorithm;
+    use jwt_authorizer::{failures, issuers::IssuerRegistry, redaction::{token_summary, RedactingLogger}};
+    use log::{debug, LevelFilter, Log, Metadata, Record};
+
+    use crate::common::{claims, issuer_config, jwks_server, TestKey};
+
+    const ISSUER: &str = "https://boto.eu.auth0.com/";
+
+    static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static INIT: Once = Once::new();
+
+    // Keeps every record that made it through the redaction
+    struct CapturingLogger;
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS.lock().unwrap().push(format!("{}: {}", record.target(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture() {
+        INIT.call_once(|| {
+            log::set_boxed_logger(Box::new(RedactingLogger::new(CapturingLogger))).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_no_log_target_emits_a_signature() {
+        capture();
+        let key = TestKey::rsa("auth0", Algorithm::RS256);
+        let server = jwks_server(&[&key]).await;
+        let registry = IssuerRegistry::from_configs(&[issuer_config(ISSUER, "api", format!("{}/jwks", server.url))], Duration::from_secs(60), Duration::from_secs(60)).await.unwrap();
+        let valid = key.sign(&claims(ISSUER, "api"));
+        let wrong_audience = key.sign(&claims(ISSUER, "other"));
+        let unknown_key = TestKey::rsa("unknown", Algorithm::RS256).sign(&claims(ISSUER, "api"));
+        for token in [&valid, &wrong_audience, &unknown_key] {
+            debug!(target: "main.token", "Token: {}", token_summary(token));
+            if let Err(error) = registry.auth_for_token(token).await.and_then(|mut auth| auth.validate_token(token)) {
+                failures::classify(&error);
+            }
+            // careless logging is caught too
+            debug!(target: "anywhere", "Authorization: Bearer {}", token);
+            debug!(target: "anywhere", "{:?}", token);
+        }
+        let records = RECORDS.lock().unwrap();
+        assert!(records.iter().any(|record| record.starts_with("main.token")));
+        assert!(records.iter().any(|record| record.starts_with("failures.classify")));
+        for token in [&valid, &wrong_audience, &unknown_key] {
+            let signature = token.rsplit('.').next().unwrap();
+            assert!(records.iter().all(|record| !record.contains(signature)), "{:?}", records);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+mod common;
+
+#[cfg(test)]
+mod telemetry_tests {
+    use std::{net::TcpListener, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+    use jsonwebtoken::Algorithm;
+    use jwt_authorizer::{auth::Auth, telemetry::{self, OtlpExporter}, utils};
+    use serde_json::Value;
+    use tracing::{info_span, Instrument};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use crate::common::{claims, jwks_server, Response, TestKey, TestServer};
+
+    const ISSUER: &str = "https://boto.eu.auth0.com/";
+    const XRAY: &str = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    fn attribute(span: &Value, key: &str) -> Value {
+        span["attributes"].as_array().unwrap().iter()
+            .find(|attribute| attribute["key"] == key)
+            .map_or(Value::Null, |attribute| attribute["value"].clone())
+    }
+
+    #[tokio::test]
+    async fn test_spans_are_exported_to_the_collector() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let requests = received.clone();
+        let collector = TestServer::start(move |request| {
+            requests.lock().unwrap().push((request.path.clone(), request.body.clone()));
+            Response::json("{}")
+        }).await;
+        let key = TestKey::rsa("auth0", Algorithm::RS256);
+        let keys_server = jwks_server(&[&key]).await;
+        let exporter = OtlpExporter::new(collector.url.clone(), "authorizer".to_string()).unwrap();
+        let _subscriber = tracing::subscriber::set_default(Registry::default().with(exporter.layer()));
+
+        let span = info_span!(target: "jwt_authorizer", "authorizer", xray_trace_header = XRAY);
+        telemetry::continue_xray_trace(&span, XRAY);
+        async {
+            let keys = utils::get_jwks(format!("{}/jwks", keys_server.url)).await.unwrap();
+            let mut auth = Auth::new(vec!["api".to_string()], ISSUER.to_string(), keys);
+            auth.validate_token(&key.sign(&claims(ISSUER, "api"))).unwrap();
+            assert!(auth.validate_token(&key.sign(&claims(ISSUER, "other"))).is_err());
+        }.instrument(span).await;
+        exporter.flush().await.unwrap();
+
+        let (path, body) = {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            received[0].clone()
+        };
+        assert_eq!(path, "/v1/traces");
+        let traces: Value = serde_json::from_str(&body).unwrap();
+        let resource = &traces["resourceSpans"][0];
+        assert_eq!(attribute(&resource["resource"], "service.name")["stringValue"], "authorizer");
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        let named = |name: &str| spans.iter().filter(|span| span["name"] == name).collect::<Vec<_>>();
+        let root = named("authorizer")[0];
+        assert!(spans.iter().all(|span| span["traceId"] == "5759e988bd862e3fe1be46a994272793"));
+        assert_eq!(root["parentSpanId"], "53995c3f42cd8ad8");
+        // unsigned fields are exported as strings
+        assert_eq!(attribute(named("get_jwks")[0], "keys")["stringValue"], "1");
+        let validations = named("validate_token");
+        assert_eq!(validations.len(), 2);
+        assert!(validations.iter().all(|span| span["parentSpanId"] == root["spanId"]));
+        assert_eq!(attribute(validations[0], "kid")["stringValue"], "auth0");
+        assert_eq!(attribute(validations[0], "algorithm")["stringValue"], "RS256");
+        assert_eq!(attribute(validations[0], "issuer")["stringValue"], ISSUER);
+        assert_eq!(attribute(validations[0], "outcome")["stringValue"], "ok");
+        assert_eq!(attribute(validations[1], "outcome")["stringValue"], "error");
+        assert_eq!(validations[1]["status"]["code"], 2);
+        // the key lookups happen within the validations
+        let lookups = named("find_jwk");
+        assert_eq!(lookups.len(), 2);
+        assert!(lookups.iter().all(|lookup| validations.iter().any(|validation| lookup["parentSpanId"] == validation["spanId"])));
+        // nothing left to send
+        exporter.flush().await.unwrap();
+        assert_eq!(collector.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unsampled_and_foreign_spans_are_not_exported() {
+        let collector = TestServer::start(|_| Response::json("{}")).await;
+        let exporter = OtlpExporter::new(collector.url.clone(), "authorizer".to_string()).unwrap();
+        let _subscriber = tracing::subscriber::set_default(Registry::default().with(exporter.layer()));
+
+        let span = info_span!(target: "jwt_authorizer", "authorizer");
+        telemetry::continue_xray_trace(&span, &XRAY.replace("Sampled=1", "Sampled=0"));
+        span.in_scope(|| info_span!(target: "jwt_authorizer", "find_jwk").in_scope(|| {}));
+        drop(span);
+        info_span!(target: "hyper", "connect").in_scope(|| {});
+        exporter.flush().await.unwrap();
+        assert_eq!(collector.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_silent_collector_does_not_hold_the_flush() {
+        // accepts connections, never answers
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", collector.local_addr().unwrap());
+        let exporter = OtlpExporter::new(url, "authorizer".to_string()).unwrap();
+        let _subscriber = tracing::subscriber::set_default(Registry::default().with(exporter.layer()));
+
+        let span = info_span!(target: "jwt_authorizer", "authorizer");
+        telemetry::continue_xray_trace(&span, XRAY);
+        span.in_scope(|| {});
+        let started = Instant::now();
+        let _ = exporter.flush().await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,82 @@
+mod common;
+
+#[cfg(test)]
+mod users_tests {
+    use std::{sync::{Arc, Mutex}, time::Duration};
+
+    use aws_sdk_dynamodb::{config::{retry::RetryConfig, BehaviorVersion, Credentials, Region}, Client, Config};
+    use jwt_authorizer::{
+        enums::{DirectoryFailureMode, FailureCategory},
+        failures,
+        users::{CachedUserDirectory, DynamoUserDirectory, UserCheck, UserDirectory},
+    };
+    use serde_json::{json, Value};
+
+    use crate::common::{Response, TestServer};
+
+    // DynamoDB Local or a stub answering like it
+    fn client(endpoint: &str) -> Client {
+        Client::from_conf(Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("eu-west-1"))
+            .credentials_provider(Credentials::new("local", "local", None, None, "tests"))
+            .endpoint_url(endpoint)
+            .retry_config(RetryConfig::disabled())
+            .build())
+    }
+
+    fn item(blocked: Value) -> Response {
+        Response::json(&json!({ "Item": { "user": { "S": "auth0|123456" }, "disabled": blocked } }).to_string())
+            .with_header("Content-Type", "application/x-amz-json-1.0")
+    }
+
+    #[tokio::test]
+    async fn test_dynamodb_directory() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let server = TestServer::start(move |request| {
+            let body: Value = serde_json::from_str(&request.body).unwrap();
+            received.lock().unwrap().push((request.headers.get("x-amz-target").cloned(), body.clone()));
+            match body["Key"]["user"]["S"].as_str() {
+                Some("auth0|blocked") => item(json!({ "BOOL": true })),
+                Some("auth0|123456") => item(json!({ "BOOL": false })),
+                Some("auth0|odd") => item(json!({ "S": "yes" })),
+                _ => Response::json("{}").with_header("Content-Type", "application/x-amz-json-1.0"),
+            }
+        }).await;
+        let directory = DynamoUserDirectory::new(client(&server.url), "users".to_string())
+            .with_key_attribute("user".to_string())
+            .with_blocked_attribute("disabled".to_string());
+        assert!(directory.is_blocked("auth0|blocked").await.unwrap());
+        assert!(!directory.is_blocked("auth0|123456").await.unwrap());
+        // no item, not blocked
+        assert!(!directory.is_blocked("auth0|unknown").await.unwrap());
+        assert!(directory.is_blocked("auth0|odd").await.is_err());
+        let (target, body) = requests.lock().unwrap()[0].clone();
+        assert_eq!(target.as_deref(), Some("DynamoDB_20120810.GetItem"));
+        assert_eq!(body["TableName"], "users");
+        assert_eq!(body["ProjectionExpression"], "#blocked");
+        assert_eq!(body["ExpressionAttributeNames"]["#blocked"], "disabled");
+        // answers are cached per principal
+        let cached = CachedUserDirectory::new(directory, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(cached.is_blocked("auth0|blocked").await.unwrap());
+        }
+        assert_eq!(server.hits(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_dynamodb() {
+        let server = TestServer::start(|_| {
+            Response::json(r#"{"__type": "com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException", "message": "Slow down"}"#)
+                .with_header("Content-Type", "application/x-amz-json-1.0")
+                .with_status(400)
+        }).await;
+        let closed = UserCheck::new(Box::new(DynamoUserDirectory::new(client(&server.url), "users".to_string())), DirectoryFailureMode::Closed);
+        let error = closed.check("auth0|123456").await.unwrap_err();
+        assert_eq!(failures::classify(&error), FailureCategory::Unavailable);
+        assert!(format!("{:#}", error).contains("ProvisionedThroughputExceededException"));
+        let open = UserCheck::new(Box::new(DynamoUserDirectory::new(client(&server.url), "users".to_string())), DirectoryFailureMode::Open);
+        open.check("auth0|123456").await.unwrap();
+    }
+}